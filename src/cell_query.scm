@@ -1,6 +1,13 @@
-(module (function_definition name: (identifier) @function parameters: (parameters) body: (block)))
-(module (expression_statement (assignment left: (identifier) @variable)))
-(module (import_statement name: (dotted_name (identifier) @import)))
-(module (import_statement name: (aliased_import name: (dotted_name (identifier)) alias: (identifier) @import)))
+(function_definition name: (identifier) @function)
+(class_definition name: (identifier) @class)
+(assignment left: (_) @variable right: (_))
+(augmented_assignment left: (_) @variable)
+(for_statement left: (_) @variable)
+(with_item alias: (_) @variable)
+(named_expression name: (identifier) @variable)
+(import_statement name: (dotted_name . (identifier) @import))
+(import_statement name: (aliased_import alias: (identifier) @import))
+(import_from_statement name: (dotted_name (identifier) @import))
+(import_from_statement name: (aliased_import alias: (identifier) @import))
 (identifier) @identifier
-(ERROR) @error
\ No newline at end of file
+(ERROR) @error
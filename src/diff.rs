@@ -0,0 +1,369 @@
+use crate::tangle::{IndexMap, IndexSet, Tangle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Port {
+    Requires,
+    Provides,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct Edge {
+    pub node: String,
+    pub port: Port,
+    pub var: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Line {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CodeChange {
+    pub node: String,
+    pub lines: Vec<Line>,
+}
+
+/// Changes needed to go from one graph to another. Nodes in nested flows are
+/// named by their path, e.g. `prep.node_2`.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct TangleDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// `(old, new)` names of nodes with the same id or leaves with the same
+    /// code, leaves only moved by renaming their sub-flow are left out
+    pub renamed: Vec<(String, String)>,
+    pub changed_code: Vec<CodeChange>,
+    pub added_edges: Vec<Edge>,
+    pub removed_edges: Vec<Edge>,
+}
+
+fn qualified_leaves(flow: &Tangle) -> IndexMap<String, &Tangle> {
+    flow.leaves()
+        .into_iter()
+        .map(|(mut path, leaf)| {
+            path.push(leaf.name().clone());
+            (path.join("."), leaf)
+        })
+        .collect()
+}
+
+/// The nested flows of a flow by their path, like `qualified_leaves`.
+fn qualified_flows<'t>(flow: &'t Tangle, path: &str, flows: &mut IndexMap<String, &'t Tangle>) {
+    if let Tangle::Dataflow { nodes, .. } = flow {
+        for (name, node) in nodes {
+            if let Tangle::Dataflow { .. } = node {
+                let path = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                flows.insert(path.clone(), node);
+                qualified_flows(node, &path, flows);
+            }
+        }
+    }
+}
+
+fn edges(leaves: &IndexMap<String, &Tangle>, rename: &IndexMap<String, String>) -> IndexSet<Edge> {
+    let mut edges: IndexSet<Edge> = Default::default();
+    for (name, leaf) in leaves {
+        let node = rename.get(name).unwrap_or(name);
+        let (requires, provides) = leaf.deps();
+        for (port, vars) in [(Port::Requires, requires), (Port::Provides, provides)] {
+            for var in vars {
+                edges.insert(Edge {
+                    node: node.clone(),
+                    port,
+                    var: var.clone(),
+                });
+            }
+        }
+    }
+    edges
+}
+
+/// Line diff through the longest common subsequence of lines.
+fn diff_lines(old: &str, new: &str) -> Vec<Line> {
+    let old: Vec<&str> = old.lines().map(|l| l.trim_end_matches('\r')).collect();
+    let new: Vec<&str> = new.lines().map(|l| l.trim_end_matches('\r')).collect();
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = vec![];
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(Line::Same(old[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(Line::Removed(old[i].to_string()));
+            i += 1;
+        } else {
+            lines.push(Line::Added(new[j].to_string()));
+            j += 1;
+        }
+    }
+    lines
+}
+
+impl Tangle {
+    pub fn diff(&self, other: &Tangle) -> TangleDiff {
+        let old = qualified_leaves(self);
+        let new = qualified_leaves(other);
+        let (mut old_flows, mut new_flows) = (IndexMap::new(), IndexMap::new());
+        qualified_flows(self, "", &mut old_flows);
+        qualified_flows(other, "", &mut new_flows);
+        let flow_renames: Vec<(String, String)> = old_flows
+            .iter()
+            .filter(|(from, _)| !new_flows.contains_key(*from))
+            .filter_map(|(from, flow)| {
+                new_flows
+                    .iter()
+                    .find(|(to, new)| !old_flows.contains_key(*to) && new.id() == flow.id())
+                    .map(|(to, _)| (from.clone(), to.clone()))
+            })
+            .collect();
+        // the path of a leaf once the flows around it are renamed
+        let moved = |path: &String| {
+            flow_renames
+                .iter()
+                .filter(|(from, _)| path.starts_with(&format!("{}.", from)))
+                .max_by_key(|(from, _)| from.len())
+                .map_or(path.clone(), |(from, to)| {
+                    format!("{}{}", to, &path[from.len()..])
+                })
+        };
+        let mut removed: Vec<String> = old
+            .keys()
+            .filter(|n| !new.contains_key(*n))
+            .cloned()
+            .collect();
+        let mut added: Vec<String> = new
+            .keys()
+            .filter(|n| !old.contains_key(*n))
+            .cloned()
+            .collect();
+
+        let mut rename: IndexMap<String, String> = Default::default();
+        for from in removed.iter() {
+            let free = |to: &&String| !rename.values().any(|r| r == *to);
+            let to = added
+                .iter()
+                .filter(free)
+                .find(|to| old[from].id() == new[*to].id())
+                .or_else(|| {
+                    added
+                        .iter()
+                        .filter(free)
+                        .find(|to| old[from].code() == new[*to].code())
+                });
+            if let Some(to) = to {
+                rename.insert(from.clone(), to.clone());
+            }
+        }
+        removed.retain(|n| !rename.contains_key(n));
+        added.retain(|n| !rename.values().any(|r| r == n));
+
+        let changed_code = old
+            .iter()
+            .filter_map(|(name, leaf)| {
+                let name = rename.get(name).unwrap_or(name);
+                let other = new.get(name)?;
+                if leaf.code() == other.code() {
+                    None
+                } else {
+                    Some(CodeChange {
+                        node: name.clone(),
+                        lines: diff_lines(leaf.code(), other.code()),
+                    })
+                }
+            })
+            .collect();
+
+        let old_edges = edges(&old, &rename);
+        let new_edges = edges(&new, &Default::default());
+        TangleDiff {
+            added,
+            removed,
+            renamed: flow_renames
+                .iter()
+                .cloned()
+                .chain(rename.into_iter().filter(|(from, to)| moved(from) != *to))
+                .collect(),
+            changed_code,
+            added_edges: new_edges.difference(&old_edges).cloned().collect(),
+            removed_edges: old_edges.difference(&new_edges).cloned().collect(),
+        }
+    }
+}
+
+impl TangleDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.renamed.is_empty()
+            && self.changed_code.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl std::fmt::Display for TangleDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for name in &self.added {
+            writeln!(f, "+ node {}", name)?;
+        }
+        for name in &self.removed {
+            writeln!(f, "- node {}", name)?;
+        }
+        for (from, to) in &self.renamed {
+            writeln!(f, "~ node {} -> {}", from, to)?;
+        }
+        for change in &self.changed_code {
+            writeln!(f, "@ node {}", change.node)?;
+            for line in &change.lines {
+                match line {
+                    Line::Same(l) => writeln!(f, "    {}", l)?,
+                    Line::Removed(l) => writeln!(f, "  - {}", l)?,
+                    Line::Added(l) => writeln!(f, "  + {}", l)?,
+                }
+            }
+        }
+        for (sign, edges) in [("+", &self.added_edges), ("-", &self.removed_edges)] {
+            for edge in edges {
+                let port = match edge.port {
+                    Port::Requires => "requires",
+                    Port::Provides => "provides",
+                };
+                writeln!(f, "{} {} {} {}", sign, edge.node, port, edge.var)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(code: &str) -> Tangle {
+        Tangle::from_code(code.to_string())
+            .unwrap()
+            .flows
+            .remove("main")
+            .unwrap()
+    }
+
+    const OLD: &str = "def load():\n    xs = [1]\n    return [xs]\n\n\
+                       def total(xs):\n    s = sum(xs)\n    return [s]\n\n\
+                       def main():\n    [xs] = load()\n    [s] = total(xs)\n    return [s]\n";
+
+    #[test]
+    fn lines() {
+        assert_eq!(
+            diff_lines("a\nb\r\nc", "a\nc\nd"),
+            vec![
+                Line::Same("a".to_string()),
+                Line::Removed("b".to_string()),
+                Line::Same("c".to_string()),
+                Line::Added("d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn same_flow() {
+        assert!(flow(OLD).diff(&flow(OLD)).is_empty());
+    }
+
+    #[test]
+    fn renames_and_edges() {
+        let new = "def load():\n    xs = [1, 2]\n    return [xs]\n\n\
+                   def add_up(xs):\n    s = sum(xs)\n    return [s]\n\n\
+                   def count(xs):\n    n = len(xs)\n    return [n]\n\n\
+                   def main():\n    [xs] = load()\n    [s] = add_up(xs)\n    [n] = count(xs)\n    \
+                   return [s, n]\n";
+        let diff = flow(OLD).diff(&flow(new));
+        assert_eq!(diff.added, vec!["count"]);
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            diff.renamed,
+            vec![("total".to_string(), "add_up".to_string())]
+        );
+        assert_eq!(diff.changed_code.len(), 1);
+        assert_eq!(diff.changed_code[0].node, "load");
+        let edge = |node: &str, port, var: &str| Edge {
+            node: node.to_string(),
+            port,
+            var: var.to_string(),
+        };
+        assert_eq!(
+            diff.added_edges,
+            vec![
+                edge("count", Port::Requires, "xs"),
+                edge("count", Port::Provides, "n")
+            ]
+        );
+        assert!(diff.removed_edges.is_empty());
+        assert_eq!(
+            diff.to_string(),
+            "+ node count\n~ node total -> add_up\n@ node load\n  - xs = [1]\n  + xs = [1, 2]\n\
+             + count requires xs\n+ count provides n\n"
+        );
+        let json: TangleDiff = serde_json::from_str(&diff.to_json()).unwrap();
+        assert_eq!(json.renamed, diff.renamed);
+    }
+
+    #[test]
+    fn renames_follow_ids() {
+        let mut new = flow(&OLD.replace("sum(xs)", "sum(xs) + 1"));
+        // same id, different name and code
+        if let Tangle::Dataflow { nodes, .. } = &mut new {
+            let mut leaf = nodes.shift_remove("total").unwrap();
+            if let Tangle::Leaf { name, .. } = &mut leaf {
+                *name = "add_up".to_string();
+            }
+            nodes.insert("add_up".to_string(), leaf);
+        }
+        let diff = flow(OLD).diff(&new);
+        assert_eq!(
+            diff.renamed,
+            vec![("total".to_string(), "add_up".to_string())]
+        );
+        assert_eq!(diff.changed_code[0].node, "add_up");
+    }
+
+    #[test]
+    fn renamed_sub_flows() {
+        let old = "def load():\n    xs = [1]\n    return [xs]\n\n\
+                   def total(xs):\n    s = sum(xs)\n    return [s]\n\n\
+                   def prep():\n    [xs] = load()\n    return [xs]\n\n\
+                   def main():\n    [xs] = prep()\n    [s] = total(xs)\n    return [s]\n";
+        let old = flow(old);
+        // the id marker written above `prep` keeps its id
+        let new = flow(&old.emit_all().unwrap().replace("prep", "prepare"));
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.renamed,
+            vec![("prep".to_string(), "prepare".to_string())]
+        );
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert!(diff.changed_code.is_empty());
+        assert!(diff.added_edges.is_empty() && diff.removed_edges.is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::tangle::{dependency_edges, topo_sort, Error, IndexMap, Tangle};
+
+enum Item {
+    Node {
+        id: String,
+        label: String,
+    },
+    /// a sub-flow with its own nodes
+    Cluster {
+        id: String,
+        label: String,
+        items: Vec<Item>,
+    },
+}
+
+/// The nodes of a flow in `topo_sort` order, sub-flows as clusters, with
+/// edges between the leaves that provide and require each variable.
+struct Graph {
+    name: String,
+    items: Vec<Item>,
+    /// (id, variable) of the inputs of the flow
+    inputs: Vec<(String, String)>,
+    /// (from, to, variable)
+    edges: Vec<(String, String, String)>,
+}
+
+fn node_id(path: &[String], name: &str) -> String {
+    let mut parts = path.to_vec();
+    parts.push(name.to_string());
+    parts.join("__")
+}
+
+/// The leaf inside `node` that provides `var`.
+fn provider(node: &Tangle, var: &str, path: &[String]) -> Option<String> {
+    match node {
+        Tangle::Leaf { name, .. } => Some(node_id(path, name)),
+        Tangle::Dataflow { name, nodes, .. } => {
+            let inner = nodes.values().rev().find(|n| n.deps().1.contains(var))?;
+            provider(inner, var, &[path, std::slice::from_ref(name)].concat())
+        }
+    }
+}
+
+/// The leaves inside `node` that require `var` from outside of it.
+fn consumers(node: &Tangle, var: &str, path: &[String]) -> Vec<String> {
+    match node {
+        Tangle::Leaf { name, .. } => vec![node_id(path, name)],
+        Tangle::Dataflow { name, nodes, .. } => {
+            if nodes.values().any(|n| n.deps().1.contains(var)) {
+                return vec![];
+            }
+            let path = [path, std::slice::from_ref(name)].concat();
+            nodes
+                .values()
+                .filter(|n| n.deps().0.contains(var))
+                .flat_map(|n| consumers(n, var, &path))
+                .collect()
+        }
+    }
+}
+
+impl Graph {
+    fn of(flow: &Tangle) -> Result<Graph, Error> {
+        let mut graph = Graph {
+            name: flow.name().clone(),
+            items: vec![],
+            inputs: vec![],
+            edges: vec![],
+        };
+        graph.items = match flow {
+            Tangle::Leaf { name, .. } => vec![Item::Node {
+                id: name.clone(),
+                label: name.clone(),
+            }],
+            Tangle::Dataflow { nodes, .. } => graph.level(nodes, &[])?,
+        };
+        let top: Vec<&Tangle> = match flow {
+            Tangle::Leaf { .. } => vec![flow],
+            Tangle::Dataflow { nodes, .. } => nodes.values().collect(),
+        };
+        let mut edges = vec![];
+        for var in flow.deps().0 {
+            let id = format!("__{}", var);
+            for node in top.iter().filter(|n| n.deps().0.contains(var)) {
+                for to in consumers(node, var, &[]) {
+                    edges.push((id.clone(), to, var.clone()));
+                }
+            }
+            graph.inputs.push((id, var.clone()));
+        }
+        // inputs come before everything else
+        graph.edges.splice(0..0, edges);
+        Ok(graph)
+    }
+
+    fn level(
+        &mut self,
+        nodes: &IndexMap<String, Tangle>,
+        path: &[String],
+    ) -> Result<Vec<Item>, Error> {
+        let ordered: Vec<&Tangle> = topo_sort(nodes.values().collect())?
+            .into_iter()
+            .flatten()
+            .map(|name| &nodes[&name])
+            .collect();
+        let mut items = vec![];
+        for node in &ordered {
+            items.push(match node {
+                Tangle::Leaf { name, .. } => Item::Node {
+                    id: node_id(path, name),
+                    label: name.clone(),
+                },
+                Tangle::Dataflow { name, nodes, .. } => Item::Cluster {
+                    id: node_id(path, name),
+                    label: name.clone(),
+                    items: self.level(nodes, &[path, std::slice::from_ref(name)].concat())?,
+                },
+            });
+        }
+        for (from, to, var) in dependency_edges(&ordered) {
+            if let Some(from) = provider(&nodes[from], var, path) {
+                for to in consumers(&nodes[to], var, path) {
+                    self.edges.push((from.clone(), to, var.clone()));
+                }
+            }
+        }
+        Ok(items)
+    }
+}
+
+fn dot_items(items: &[Item], depth: usize, out: &mut Vec<String>) {
+    let indent = "    ".repeat(depth);
+    for item in items {
+        match item {
+            Item::Node { id, label } => {
+                out.push(format!("{}\"{}\" [label=\"{}\"];", indent, id, label))
+            }
+            Item::Cluster { id, label, items } => {
+                out.push(format!("{}subgraph \"cluster_{}\" {{", indent, id));
+                out.push(format!("{}    label=\"{}\";", indent, label));
+                dot_items(items, depth + 1, out);
+                out.push(format!("{}}}", indent));
+            }
+        }
+    }
+}
+
+/// Mermaid ids can't be quoted, and ids like `end` or `graph` are keywords.
+fn mermaid_id(id: &str) -> String {
+    format!("n_{}", id)
+}
+
+fn mermaid_items(items: &[Item], depth: usize, out: &mut Vec<String>) {
+    let indent = "    ".repeat(depth);
+    for item in items {
+        match item {
+            Item::Node { id, label } => {
+                out.push(format!("{}{}[\"{}\"]", indent, mermaid_id(id), label))
+            }
+            Item::Cluster { id, label, items } => {
+                out.push(format!(
+                    "{}subgraph {}[\"{}\"]",
+                    indent,
+                    mermaid_id(id),
+                    label
+                ));
+                mermaid_items(items, depth + 1, out);
+                out.push(format!("{}end", indent));
+            }
+        }
+    }
+}
+
+impl Tangle {
+    /// The dependency graph in Graphviz DOT, with a cluster for each sub-flow.
+    pub fn to_dot(&self) -> Result<String, Error> {
+        let graph = Graph::of(self)?;
+        let mut out = vec![format!("digraph \"{}\" {{", graph.name)];
+        for (id, var) in &graph.inputs {
+            out.push(format!(
+                "    \"{}\" [label=\"{}\", shape=plaintext];",
+                id, var
+            ));
+        }
+        dot_items(&graph.items, 1, &mut out);
+        for (from, to, var) in &graph.edges {
+            out.push(format!(
+                "    \"{}\" -> \"{}\" [label=\"{}\"];",
+                from, to, var
+            ));
+        }
+        out.push("}".to_string());
+        Ok(out.join("\n") + "\n")
+    }
+
+    /// The dependency graph as a Mermaid flowchart, with a subgraph for each
+    /// sub-flow.
+    pub fn to_mermaid(&self) -> Result<String, Error> {
+        let graph = Graph::of(self)?;
+        let mut out = vec!["flowchart TD".to_string()];
+        for (id, var) in &graph.inputs {
+            out.push(format!("    {}([\"{}\"])", mermaid_id(id), var));
+        }
+        mermaid_items(&graph.items, 1, &mut out);
+        for (from, to, var) in &graph.edges {
+            out.push(format!(
+                "    {} -->|{}| {}",
+                mermaid_id(from),
+                var,
+                mermaid_id(to)
+            ));
+        }
+        Ok(out.join("\n") + "\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow() -> Tangle {
+        let code = "def end(x):\n    y = x + 1\n    return [y]\n\n\
+                    def inc(y):\n    z = y * 2\n    return [z]\n\n\
+                    def prep(y):\n    [z] = inc(y)\n    return [z]\n\n\
+                    def main(x):\n    [y] = end(x)\n    [z] = prep(y)\n    return [z]\n";
+        Tangle::from_code(code.to_string())
+            .unwrap()
+            .flows
+            .remove("main")
+            .unwrap()
+    }
+
+    #[test]
+    fn dot() {
+        assert_eq!(
+            flow().to_dot().unwrap(),
+            "digraph \"main\" {\n    \
+               \"__x\" [label=\"x\", shape=plaintext];\n    \
+               \"end\" [label=\"end\"];\n    \
+               subgraph \"cluster_prep\" {\n        \
+                 label=\"prep\";\n        \
+                 \"prep__inc\" [label=\"inc\"];\n    \
+               }\n    \
+               \"__x\" -> \"end\" [label=\"x\"];\n    \
+               \"end\" -> \"prep__inc\" [label=\"y\"];\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn mermaid_ids_are_not_keywords() {
+        assert_eq!(
+            flow().to_mermaid().unwrap(),
+            "flowchart TD\n    \
+               n___x([\"x\"])\n    \
+               n_end[\"end\"]\n    \
+               subgraph n_prep[\"prep\"]\n        \
+                 n_prep__inc[\"inc\"]\n    \
+               end\n    \
+               n___x -->|x| n_end\n    \
+               n_end -->|y| n_prep__inc\n"
+        );
+    }
+}
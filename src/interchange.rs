@@ -0,0 +1,842 @@
+//! The JSON other tools read and write tangle graphs in, described by
+//! `graph.schema.json`. Documents carry a `version`, bumped whenever a change
+//! would make an older tangle misread a document.
+
+use serde_json::{Map, Value};
+
+use crate::tangle::{
+    id_for_name, topo_sort, Error, ErrorKind, IndexMap, IndexSet, Span, Tangle, Trivia,
+};
+
+pub const VERSION: u64 = 1;
+
+pub const SCHEMA: &str = include_str!("graph.schema.json");
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Document {
+    version: u64,
+    flow: Node,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Node {
+    Leaf {
+        name: String,
+        id: String,
+        code: String,
+        requires: IndexSet<String>,
+        provides: IndexSet<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        position: Option<Position>,
+        #[serde(default)]
+        metadata: LeafMetadata,
+    },
+    Flow {
+        name: String,
+        /// derived from the name when missing, see `id_for_name`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        requires: IndexSet<String>,
+        provides: IndexSet<String>,
+        nodes: Vec<Node>,
+        #[serde(default)]
+        metadata: FlowMetadata,
+    },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+#[serde(default)]
+struct LeafMetadata {
+    annotations: IndexMap<String, String>,
+    defaults: IndexMap<String, String>,
+    note: String,
+    trivia: Trivia,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+#[serde(default)]
+struct FlowMetadata {
+    annotations: IndexMap<String, String>,
+    trivia: Trivia,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Position {
+    pub row: usize,
+    pub column: usize,
+}
+
+/// How the editor shows a leaf, kept in the document next to the leaf.
+#[derive(Debug, Clone, Default)]
+pub struct View {
+    pub position: Option<Position>,
+    pub note: String,
+}
+
+/// A place in a document that does not follow the schema.
+#[derive(Debug, Clone)]
+pub struct SchemaError {
+    /// JSON pointer to the offending value, like `/flow/nodes/0/requires/1`
+    pub path: String,
+    pub message: String,
+    /// where the value is in the JSON text
+    pub span: Option<Span>,
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = if self.path.is_empty() {
+            "/"
+        } else {
+            &self.path
+        };
+        write!(f, "{}: {}", path, self.message)
+    }
+}
+
+fn to_node(flow: &Tangle, views: &IndexMap<String, View>) -> Result<Node, Error> {
+    Ok(match flow {
+        Tangle::Leaf {
+            name,
+            provides,
+            requires,
+            code,
+            annotations,
+            id,
+            defaults,
+            trivia,
+        } => {
+            let view = views.get(id).cloned().unwrap_or_default();
+            Node::Leaf {
+                name: name.clone(),
+                id: id.clone(),
+                code: code.clone(),
+                requires: requires.clone(),
+                provides: provides.clone(),
+                position: view.position,
+                metadata: LeafMetadata {
+                    annotations: annotations.clone(),
+                    defaults: defaults.clone(),
+                    note: view.note,
+                    trivia: trivia.clone(),
+                },
+            }
+        }
+        Tangle::Dataflow {
+            name,
+            nodes,
+            provides,
+            requires,
+            annotations,
+            id,
+            trivia,
+        } => Node::Flow {
+            name: name.clone(),
+            id: Some(id.clone()),
+            requires: requires.clone(),
+            provides: provides.clone(),
+            nodes: topo_sort(nodes.values().collect())?
+                .into_iter()
+                .flatten()
+                .map(|name| to_node(&nodes[&name], views))
+                .collect::<Result<_, _>>()?,
+            metadata: FlowMetadata {
+                annotations: annotations.clone(),
+                trivia: trivia.clone(),
+            },
+        },
+    })
+}
+
+fn from_node(node: Node, views: &mut IndexMap<String, View>) -> Tangle {
+    match node {
+        Node::Leaf {
+            name,
+            id,
+            code,
+            requires,
+            provides,
+            position,
+            metadata,
+        } => {
+            views.insert(
+                id.clone(),
+                View {
+                    position,
+                    note: metadata.note,
+                },
+            );
+            Tangle::Leaf {
+                name,
+                provides,
+                requires,
+                code,
+                annotations: metadata.annotations,
+                id,
+                defaults: metadata.defaults,
+                trivia: metadata.trivia,
+            }
+        }
+        Node::Flow {
+            name,
+            id,
+            requires,
+            provides,
+            nodes,
+            metadata,
+        } => Tangle::Dataflow {
+            id: id.unwrap_or_else(|| id_for_name(&name)),
+            name,
+            nodes: nodes
+                .into_iter()
+                .map(|node| from_node(node, views))
+                .map(|node| (node.name().clone(), node))
+                .collect(),
+            provides,
+            requires,
+            annotations: metadata.annotations,
+            trivia: metadata.trivia,
+        },
+    }
+}
+
+/// Writes a flow with the view of each of its leaves, by leaf id.
+pub fn export(flow: &Tangle, views: &IndexMap<String, View>) -> Result<String, Error> {
+    let document = Document {
+        version: VERSION,
+        flow: to_node(flow, views)?,
+    };
+    Ok(serde_json::to_string_pretty(&document).unwrap() + "\n")
+}
+
+/// Reads a flow and the views of its leaves, by leaf id, reporting every
+/// place the document breaks the schema.
+pub fn import(json: &str) -> Result<(Tangle, IndexMap<String, View>), Error> {
+    let not_json = |err: &serde_json::Error, message: String| {
+        // serde counts lines and columns from 1
+        let at = json
+            .split_inclusive('\n')
+            .take(err.line().saturating_sub(1))
+            .map(str::len)
+            .sum::<usize>()
+            + err.column().saturating_sub(1);
+        let at = at.min(json.len());
+        ErrorKind::InvalidGraph(vec![SchemaError {
+            path: String::new(),
+            message,
+            span: Some(Span::in_text(json, at..at)),
+        }])
+    };
+    let value: Value =
+        serde_json::from_str(json).map_err(|err| not_json(&err, format!("not JSON: {}", err)))?;
+    let mut checker = Checker::default();
+    checker.document(&value);
+    if !checker.errors.is_empty() {
+        return Err(located(json, checker.errors));
+    }
+    // read from the text again, `Value` loses the order of objects
+    let document: Document =
+        serde_json::from_str(json).map_err(|err| not_json(&err, err.to_string()))?;
+    let mut views = IndexMap::new();
+    let flow = from_node(document.flow, &mut views);
+    let mut errors = vec![];
+    check_order(&flow, "/flow", &mut errors);
+    if !errors.is_empty() {
+        return Err(located(json, errors));
+    }
+    Ok((flow, views))
+}
+
+fn located(json: &str, mut errors: Vec<SchemaError>) -> Error {
+    for err in &mut errors {
+        err.span = locate(json, &err.path);
+    }
+    ErrorKind::InvalidGraph(errors).into()
+}
+
+/// The first line of the value at the JSON pointer `path` in `json`, which
+/// has to be valid JSON.
+fn locate(json: &str, path: &str) -> Option<Span> {
+    let bytes = json.as_bytes();
+    let skip_space = |mut at: usize| {
+        while at < bytes.len() && bytes[at].is_ascii_whitespace() {
+            at += 1;
+        }
+        at
+    };
+    // the end of the value starting at `at`
+    let skip_value = |mut at: usize| {
+        let mut depth = 0;
+        let mut in_string = false;
+        while at < bytes.len() {
+            match bytes[at] {
+                b'\\' if in_string => at += 1,
+                b'"' => {
+                    in_string = !in_string;
+                    if !in_string && depth == 0 {
+                        return at + 1;
+                    }
+                }
+                b'{' | b'[' if !in_string => depth += 1,
+                b'}' | b']' if !in_string => {
+                    if depth == 0 {
+                        return at;
+                    }
+                    depth -= 1;
+                    if depth == 0 {
+                        return at + 1;
+                    }
+                }
+                b',' if !in_string && depth == 0 => return at,
+                c if !in_string && depth == 0 && c.is_ascii_whitespace() => return at,
+                _ => {}
+            }
+            at += 1;
+        }
+        at
+    };
+    let mut at = skip_space(0);
+    for token in path.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        let open = *bytes.get(at)?;
+        at = skip_space(at + 1);
+        let mut index = 0;
+        loop {
+            if matches!(bytes.get(at)?, b'}' | b']') {
+                return None;
+            }
+            let found = if open == b'{' {
+                let end = skip_value(at);
+                let key: String = serde_json::from_str(&json[at..end]).ok()?;
+                at = skip_space(skip_space(end) + 1);
+                key == token
+            } else {
+                index += 1;
+                token.parse::<usize>().ok()? == index - 1
+            };
+            if found {
+                break;
+            }
+            at = skip_space(skip_value(at));
+            if bytes.get(at)? == &b',' {
+                at = skip_space(at + 1);
+            }
+        }
+    }
+    let end = skip_value(at);
+    let end = json[at..end].find('\n').map_or(end, |n| at + n);
+    Some(Span::in_text(json, at..end))
+}
+
+/// Checks a document without keeping what it describes.
+pub fn validate(json: &str) -> Result<(), Error> {
+    import(json).map(|_| ())
+}
+
+/// Reports the flows whose nodes can't be ordered, like through a cycle.
+fn check_order(flow: &Tangle, path: &str, errors: &mut Vec<SchemaError>) {
+    if let Tangle::Dataflow { nodes, .. } = flow {
+        if let Err(err) = topo_sort(nodes.values().collect()) {
+            errors.push(SchemaError {
+                path: path.to_string(),
+                message: err.to_string(),
+                span: None,
+            });
+        }
+        for (i, node) in nodes.values().enumerate() {
+            check_order(node, &format!("{}/nodes/{}", path, i), errors);
+        }
+    }
+}
+
+/// Walks a document the way `graph.schema.json` describes it.
+#[derive(Default)]
+struct Checker {
+    errors: Vec<SchemaError>,
+    /// path of the node with each id
+    ids: IndexMap<String, String>,
+}
+
+/// A name as `graph.schema.json` matches it: ASCII names follow Python, other
+/// characters up to U+FFFF pass, as regexes read `\w` differently.
+fn is_identifier(name: &str) -> bool {
+    let other = |c: char| ('\u{80}'..='\u{ffff}').contains(&c);
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || other(c))
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || other(c))
+}
+
+fn field(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+impl Checker {
+    fn error(&mut self, path: &str, message: String) {
+        self.errors.push(SchemaError {
+            path: path.to_string(),
+            message,
+            span: None,
+        });
+    }
+
+    /// The object at `path`, after reporting missing and unknown fields.
+    fn object<'v>(
+        &mut self,
+        value: &'v Value,
+        path: &str,
+        required: &[&str],
+        optional: &[&str],
+    ) -> Option<&'v Map<String, Value>> {
+        let object = match value.as_object() {
+            Some(object) => object,
+            None => {
+                self.error(path, "expected an object".to_string());
+                return None;
+            }
+        };
+        for key in required {
+            if !object.contains_key(*key) {
+                self.error(path, format!("missing field `{}`", key));
+            }
+        }
+        for key in object.keys() {
+            if !required.contains(&key.as_str()) && !optional.contains(&key.as_str()) {
+                self.error(&field(path, key), "unknown field".to_string());
+            }
+        }
+        Some(object)
+    }
+
+    fn string<'v>(&mut self, value: Option<&'v Value>, path: &str) -> Option<&'v str> {
+        let value = value?;
+        if value.as_str().is_none() {
+            self.error(path, "expected a string".to_string());
+        }
+        value.as_str()
+    }
+
+    fn identifier(&mut self, value: Option<&Value>, path: &str) -> Option<String> {
+        let name = self.string(value, path)?;
+        if !is_identifier(name) {
+            self.error(path, format!("`{}` is not a Python identifier", name));
+            return None;
+        }
+        Some(name.to_string())
+    }
+
+    fn id(&mut self, value: Option<&Value>, path: &str) {
+        if let Some(id) = self.string(value, path) {
+            if id.is_empty() {
+                self.error(path, "empty id".to_string());
+            } else if let Some(other) = self.ids.insert(id.to_string(), path.to_string()) {
+                self.error(path, format!("id `{}` is already used at {}", id, other));
+            }
+        }
+    }
+
+    fn variables(&mut self, value: Option<&Value>, path: &str) -> IndexSet<String> {
+        let mut vars = IndexSet::new();
+        let items = match value {
+            Some(Value::Array(items)) => items,
+            Some(_) => {
+                self.error(path, "expected an array of variables".to_string());
+                return vars;
+            }
+            None => return vars,
+        };
+        for (i, item) in items.iter().enumerate() {
+            let path = format!("{}/{}", path, i);
+            if let Some(var) = self.identifier(Some(item), &path) {
+                if !vars.insert(var.clone()) {
+                    self.error(&path, format!("`{}` is listed twice", var));
+                }
+            }
+        }
+        vars
+    }
+
+    fn strings(&mut self, value: Option<&Value>, path: &str) {
+        match value {
+            Some(Value::Object(object)) => {
+                for (key, value) in object {
+                    self.string(Some(value), &field(path, key));
+                }
+            }
+            Some(_) => self.error(path, "expected an object of strings".to_string()),
+            None => {}
+        }
+    }
+
+    fn trivia(&mut self, value: Option<&Value>, path: &str) {
+        let value = match value {
+            Some(value) => value,
+            None => return,
+        };
+        let texts = ["before", "before_return", "after_return", "after"];
+        let maps = ["node_before", "node_inline"];
+        let all: Vec<&str> = texts
+            .iter()
+            .chain(&maps)
+            .chain(&["decorators"])
+            .copied()
+            .collect();
+        let object = match self.object(value, path, &[], &all) {
+            Some(object) => object,
+            None => return,
+        };
+        for key in texts {
+            self.string(object.get(key), &field(path, key));
+        }
+        for key in maps {
+            self.strings(object.get(key), &field(path, key));
+        }
+        let path = field(path, "decorators");
+        match object.get("decorators") {
+            Some(Value::Array(items)) => {
+                for (i, item) in items.iter().enumerate() {
+                    self.string(Some(item), &format!("{}/{}", path, i));
+                }
+            }
+            Some(_) => self.error(&path, "expected an array of strings".to_string()),
+            None => {}
+        }
+    }
+
+    fn document(&mut self, value: &Value) {
+        let object = match self.object(value, "", &["version", "flow"], &[]) {
+            Some(object) => object,
+            None => return,
+        };
+        match object.get("version") {
+            Some(version) => match version.as_u64() {
+                Some(VERSION) => {}
+                Some(v) if v > VERSION => self.error(
+                    "/version",
+                    format!(
+                        "version {} is newer than the {} this tangle reads",
+                        v, VERSION
+                    ),
+                ),
+                _ => self.error("/version", format!("unknown version {}", version)),
+            },
+            // without a version nothing else can be trusted
+            None => return,
+        }
+        if let Some(flow) = object.get("flow") {
+            self.node(flow, "/flow");
+        }
+    }
+
+    /// Checks a node, returning its name, requires and provides.
+    fn node(
+        &mut self,
+        value: &Value,
+        path: &str,
+    ) -> Option<(String, IndexSet<String>, IndexSet<String>)> {
+        let kind = value.get("kind").and_then(|kind| kind.as_str());
+        match kind {
+            Some("leaf") => self.leaf(value, path),
+            Some("flow") => self.flow(value, path),
+            Some(kind) => {
+                self.error(
+                    &field(path, "kind"),
+                    format!("unknown kind `{}`, expected `leaf` or `flow`", kind),
+                );
+                None
+            }
+            None => {
+                if value.is_object() {
+                    self.error(path, "missing field `kind`, `leaf` or `flow`".to_string());
+                } else {
+                    self.error(path, "expected an object".to_string());
+                }
+                None
+            }
+        }
+    }
+
+    fn leaf(
+        &mut self,
+        value: &Value,
+        path: &str,
+    ) -> Option<(String, IndexSet<String>, IndexSet<String>)> {
+        let object = self.object(
+            value,
+            path,
+            &["kind", "name", "id", "code", "requires", "provides"],
+            &["position", "metadata"],
+        )?;
+        let name = self.identifier(object.get("name"), &field(path, "name"));
+        self.id(object.get("id"), &field(path, "id"));
+        self.string(object.get("code"), &field(path, "code"));
+        let requires = self.variables(object.get("requires"), &field(path, "requires"));
+        let provides = self.variables(object.get("provides"), &field(path, "provides"));
+        if let Some(position) = object.get("position") {
+            let path = field(path, "position");
+            if let Some(position) = self.object(position, &path, &["row", "column"], &[]) {
+                for (key, value) in position {
+                    if value.as_u64().is_none() {
+                        self.error(&field(&path, key), "expected a whole number".to_string());
+                    }
+                }
+            }
+        }
+        if let Some(metadata) = object.get("metadata") {
+            let path = field(path, "metadata");
+            let optional = ["annotations", "defaults", "note", "trivia"];
+            if let Some(metadata) = self.object(metadata, &path, &[], &optional) {
+                self.strings(metadata.get("annotations"), &field(&path, "annotations"));
+                self.strings(metadata.get("defaults"), &field(&path, "defaults"));
+                self.string(metadata.get("note"), &field(&path, "note"));
+                self.trivia(metadata.get("trivia"), &field(&path, "trivia"));
+            }
+        }
+        Some((name?, requires, provides))
+    }
+
+    fn flow(
+        &mut self,
+        value: &Value,
+        path: &str,
+    ) -> Option<(String, IndexSet<String>, IndexSet<String>)> {
+        let object = self.object(
+            value,
+            path,
+            &["kind", "name", "requires", "provides", "nodes"],
+            &["id", "metadata"],
+        )?;
+        let name = self.identifier(object.get("name"), &field(path, "name"));
+        self.id(object.get("id"), &field(path, "id"));
+        let requires = self.variables(object.get("requires"), &field(path, "requires"));
+        let provides = self.variables(object.get("provides"), &field(path, "provides"));
+        if let Some(metadata) = object.get("metadata") {
+            let path = field(path, "metadata");
+            if let Some(metadata) = self.object(metadata, &path, &[], &["annotations", "trivia"]) {
+                self.strings(metadata.get("annotations"), &field(&path, "annotations"));
+                self.trivia(metadata.get("trivia"), &field(&path, "trivia"));
+            }
+        }
+        let nodes_path = field(path, "nodes");
+        let items = match object.get("nodes") {
+            Some(Value::Array(items)) => items,
+            Some(_) => {
+                self.error(&nodes_path, "expected an array of nodes".to_string());
+                return None;
+            }
+            None => return None,
+        };
+        let mut names: IndexMap<String, String> = IndexMap::new();
+        let mut provided: IndexSet<String> = IndexSet::new();
+        let mut nodes = vec![];
+        for (i, item) in items.iter().enumerate() {
+            let path = format!("{}/{}", nodes_path, i);
+            if let Some((name, node_requires, node_provides)) = self.node(item, &path) {
+                if let Some(other) = names.insert(name.clone(), path.clone()) {
+                    self.error(
+                        &field(&path, "name"),
+                        format!("`{}` is already the name of {}", name, other),
+                    );
+                }
+                provided.extend(node_provides.iter().cloned());
+                nodes.push((path, node_requires));
+            }
+        }
+        // what a broken node provides is unknown, so don't guess at the rest
+        if nodes.len() < items.len() {
+            return Some((name?, requires, provides));
+        }
+        for (path, node_requires) in nodes {
+            for (i, var) in node_requires.iter().enumerate() {
+                if !provided.contains(var) && !requires.contains(var) {
+                    self.error(
+                        &format!("{}/requires/{}", path, i),
+                        format!(
+                            "no node provides `{}` and the flow does not require it",
+                            var
+                        ),
+                    );
+                }
+            }
+        }
+        for (i, var) in provides.iter().enumerate() {
+            if !provided.contains(var) {
+                self.error(
+                    &format!("{}/provides/{}", path, i),
+                    format!("no node of the flow provides `{}`", var),
+                );
+            }
+        }
+        Some((name?, requires, provides))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(json: &str) -> Vec<SchemaError> {
+        match import(json).map_err(Error::into_kind) {
+            Err(ErrorKind::InvalidGraph(errors)) => errors,
+            Err(err) => panic!("{}", Error::from(err)),
+            Ok(_) => panic!("the document is valid"),
+        }
+    }
+
+    #[test]
+    fn schema_errors_are_located() {
+        let json = "{\n  \"version\": 1,\n  \"flow\": {\n    \"kind\": \"leaf\",\n    \
+                    \"name\": \"a\",\n    \"id\": \"1\",\n    \"code\": \"x = 1\",\n    \
+                    \"requires\": [\"x\", 3],\n    \"provides\": []\n  }\n}";
+        let errors = errors(json);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "/flow/requires/1");
+        let span = errors[0].span.as_ref().unwrap();
+        assert_eq!(&json[span.bytes.clone()], "3");
+        assert_eq!((span.start.row, span.start.column), (7, 22));
+    }
+
+    #[test]
+    fn parse_errors_are_located() {
+        let errors = errors("{\n  \"version\": 1,\n  oops\n}");
+        assert_eq!(errors[0].span.as_ref().unwrap().start.row, 2);
+    }
+
+    #[test]
+    fn locate() {
+        let json = r#"{"a": {"b~/c": [1, {"d": "e"}]}, "f": true}"#;
+        let at = |path| super::locate(json, path).map(|span| json[span.bytes].to_string());
+        assert_eq!(at("/f").as_deref(), Some("true"));
+        assert_eq!(at("/a/b~0~1c/1/d").as_deref(), Some("\"e\""));
+        assert_eq!(at("/a/b~0~1c/1").as_deref(), Some("{\"d\": \"e\"}"));
+        assert_eq!(at("/a/b~0~1c/2"), None);
+        assert_eq!(at("/g"), None);
+    }
+
+    /// A document using every part of the format.
+    fn document() -> String {
+        let code = "@cache\ndef load(scale: float = 1.0):\n    xs = [scale]\n    return [xs]\n\n\
+                    def total(xs):\n    s = sum(xs)  # all\n    return [s]\n\n\
+                    def prep(xs):\n    [s] = total(xs)\n    return [s]\n\n\
+                    def main():\n    [xs] = load()\n    [s] = prep(xs)\n    return [s]\n";
+        let flow = Tangle::from_code(code.to_string())
+            .unwrap()
+            .flows
+            .remove("main")
+            .unwrap();
+        let views = flow
+            .leaves()
+            .iter()
+            .enumerate()
+            .map(|(row, (_, leaf))| {
+                let view = View {
+                    position: Some(Position { row, column: 0 }),
+                    note: "a *note*".to_string(),
+                };
+                (leaf.id().clone(), view)
+            })
+            .collect();
+        export(&flow, &views).unwrap()
+    }
+
+    #[test]
+    fn exports_follow_the_schema() {
+        use pyo3::prelude::*;
+        pyo3::prepare_freethreaded_python();
+        let json = document();
+        assert!(import(&json).is_ok());
+        Python::with_gil(|py| -> PyResult<()> {
+            // the `test` extra of pyproject.toml
+            let jsonschema = match py.import("jsonschema") {
+                Ok(jsonschema) => jsonschema,
+                Err(err) if err.is_instance::<pyo3::exceptions::PyImportError>(py) => {
+                    eprintln!("skipped, `jsonschema` is not installed");
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            let loads = py.import("json")?.getattr("loads")?;
+            let schema = loads.call1((SCHEMA,))?;
+            let document = loads.call1((json.as_str(),))?;
+            jsonschema.call_method1("validate", (document, schema))?;
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn identifiers_follow_the_schema() {
+        use pyo3::prelude::*;
+        pyo3::prepare_freethreaded_python();
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        let pattern = schema["$defs"]["identifier"]["pattern"].as_str().unwrap();
+        let names = [
+            "x", "_1", "données", "π", "x²", "²x", "1x", "a-b", "e\u{301}", "", "€", "𝑥",
+        ];
+        Python::with_gil(|py| -> PyResult<()> {
+            let search = py.import("re")?.getattr("search")?;
+            for name in names {
+                let matched = !search.call1((pattern, name))?.is_none();
+                assert_eq!(is_identifier(name), matched, "{:?}", name);
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn invalid_documents_point_at_the_value() {
+        let valid: Value = serde_json::from_str(&document()).unwrap();
+        let cases: Vec<(&str, Value, Vec<&str>)> = vec![
+            ("/version", Value::from(2), vec!["/version"]),
+            ("/flow/name", Value::from("a b"), vec!["/flow/name"]),
+            (
+                "/flow/nodes/0/kind",
+                Value::from("cell"),
+                vec!["/flow/nodes/0/kind"],
+            ),
+            (
+                "/flow/nodes/0/id",
+                Value::from(""),
+                vec!["/flow/nodes/0/id"],
+            ),
+            (
+                "/flow/nodes/1/nodes/0/id",
+                valid["flow"]["nodes"][0]["id"].clone(),
+                vec!["/flow/nodes/1/nodes/0/id"],
+            ),
+            (
+                "/flow/nodes/1/requires/0",
+                Value::from("x"),
+                vec![
+                    "/flow/nodes/1/nodes/0/requires/0",
+                    "/flow/nodes/1/requires/0",
+                ],
+            ),
+            (
+                "/flow/nodes/0/position/row",
+                Value::from(-1),
+                vec!["/flow/nodes/0/position/row"],
+            ),
+            (
+                "/flow/nodes/0/metadata/trivia/decorators/0",
+                Value::from(1),
+                vec!["/flow/nodes/0/metadata/trivia/decorators/0"],
+            ),
+            (
+                "/flow/nodes/0/metadata/defaults/scale",
+                Value::from(1.0),
+                vec!["/flow/nodes/0/metadata/defaults/scale"],
+            ),
+        ];
+        for (at, value, expected) in cases {
+            let mut document = valid.clone();
+            *document.pointer_mut(at).unwrap() = value;
+            let json = serde_json::to_string_pretty(&document).unwrap();
+            let paths: Vec<String> = errors(&json).into_iter().map(|err| err.path).collect();
+            assert_eq!(paths, expected, "{}", at);
+        }
+    }
+}
@@ -25,7 +25,13 @@ use std::rc::Rc;
 extern crate lazy_static;
 extern crate topological_sort;
 
+mod diff;
+mod graph;
 mod highlight;
+mod interchange;
+mod notebook;
+mod queries;
+mod scope;
 mod tangle;
 mod text_buffer;
 
@@ -37,8 +43,19 @@ struct EditableNode {
     #[serde(skip)]
     pub leaf: tangle::Tangle,
     name: String,
+    /// stable id of `leaf`, kept across renames and reloads
+    #[serde(default = "tangle::fresh_id")]
+    pub id: String,
+    /// names of the sub-flows this leaf is nested in, outermost first
+    pub flow: Vec<String>,
+    /// markdown kept from an imported notebook
+    pub note: String,
+    /// the exception the node raised last
     #[serde(skip)]
     last_output: Option<Py<PyAny>>,
+    /// what the node returned when it last ran without raising
+    #[serde(skip)]
+    last_result: Option<Py<PyAny>>,
     #[serde(skip)]
     highlighting: Option<LayoutJob>,
     #[serde(skip)]
@@ -53,6 +70,12 @@ struct EditableNode {
     pub debug_output_rect: Option<egui::Rect>,
     #[serde(skip)]
     pub changed: bool,
+    /// whether the code editor had focus last frame
+    #[serde(skip)]
+    pub editing: bool,
+    /// why the last split of this node failed
+    #[serde(skip)]
+    pub split_error: Option<String>,
     pub changed_output: IndexSet<String>,
 }
 
@@ -66,6 +89,45 @@ struct TangleApp {
     syntax_theme: CodeTheme,
     last_id: usize,
     bfs_layout: Option<Vec<Vec<String>>>,
+    /// the flow being edited, one of `flows`
+    flow_name: String,
+    #[serde(skip)]
+    flows: IndexMap<String, tangle::Tangle>,
+    #[serde(skip)]
+    flow_report: String,
+    /// the edited flow as of the last save, to diff against
+    saved_flow: Option<tangle::Tangle>,
+    diff_as_json: bool,
+    /// the diff shown, with the `revision` and format it is for
+    #[serde(skip)]
+    diff_text: Option<(u64, bool, String)>,
+    /// `compile` as of a `revision`
+    #[serde(skip)]
+    compiled: Option<(u64, Result<tangle::Tangle, String>)>,
+    /// `tangle::find_mutations` of the nodes as of a `revision`
+    #[serde(skip)]
+    mutations: Option<(u64, Vec<tangle::Mutation>)>,
+    /// path of the last imported notebook
+    import_path: String,
+    /// markdown after the last cell of the imported notebook
+    #[serde(default)]
+    end_note: String,
+    /// whether exported scripts run independent nodes concurrently
+    async_script: bool,
+    /// variables exports are sliced down to, comma separated, all if empty
+    slice_vars: String,
+    #[serde(skip)]
+    import_report: String,
+    /// path of the last imported or exported graph `.json`
+    #[serde(default)]
+    graph_path: String,
+    #[serde(skip)]
+    graph_report: String,
+    /// directory of the query pack in use, empty for the built-in queries
+    #[serde(default)]
+    query_pack: String,
+    #[serde(skip)]
+    query_report: String,
     #[serde(skip)]
     curr_module: Option<Py<PyModule>>,
     #[serde(skip)]
@@ -155,11 +217,16 @@ impl PyGUI {
         }) {
             self.nodes.get_mut(name).unwrap().debug_output_rect =
                 Some(window_response.response.rect);
+            let node = self.nodes.get_mut(name).unwrap();
             match window_response.inner.unwrap() {
-                Ok(ret) => Ok(ret),
+                Ok(ret) => {
+                    node.last_output = None;
+                    node.last_result = Some(ret.into_py(py));
+                    Ok(ret)
+                }
                 Err(exn) => {
-                    self.nodes.get_mut(name).unwrap().last_output =
-                        Some(exn.pvalue(py).into_py(py));
+                    node.last_output = Some(exn.pvalue(py).into_py(py));
+                    node.last_result = None;
                     Err(exn)
                 }
             }
@@ -308,21 +375,31 @@ impl PartialEq for EditableNode {
 
 impl Eq for EditableNode {}
 
+/// The `SyntaxError` shown in place of the output of a cell that can't be read.
+fn cell_error(err: &tangle::Error, code: &str, name: &str) -> PyErr {
+    pyo3::exceptions::PySyntaxError::new_err(err.render(code, name))
+}
+
 impl EditableNode {
     fn new(name: &str, code: &str) -> Self {
         let mut last_output = None;
         let (leaf, ident) = match tangle::Tangle::from_cell(name.to_string(), &code.to_string()) {
             Ok((leaf, ident)) => (leaf, ident),
             Err((err, (partial_leaf, ident))) => {
+                let err = cell_error(&err, code, name);
                 Python::with_gil(|py| last_output = Some(err.pvalue(py).into_py(py)));
                 (partial_leaf, ident)
             }
         };
         Self {
             name: leaf.name().clone(),
+            id: leaf.id().clone(),
             code: code.into(),
             leaf,
+            flow: vec![],
+            note: String::new(),
             last_output,
+            last_result: None,
             highlighting: None,
             response: None,
             debug_output_rect: None,
@@ -331,6 +408,8 @@ impl EditableNode {
             changed_output: Default::default(),
             ident,
             changed: false,
+            editing: false,
+            split_error: None,
         }
     }
 
@@ -378,15 +457,20 @@ impl EditableNode {
         ui: &mut egui::Ui,
         theme: &CodeTheme,
         exec: bool,
-    ) -> (bool, std::sync::Arc<egui::Galley>) {
+        conflicts: &[&String],
+        mutations: &[&tangle::Mutation],
+    ) -> (bool, bool, std::sync::Arc<egui::Galley>) {
         let Self {
             name,
             code,
             last_output,
             highlighting,
             leaf,
+            flow,
+            note,
             text_off,
             ident,
+            split_error,
             ..
         } = self;
         let job = highlighting.get_or_insert_with(|| highlight::highlight(theme, code));
@@ -404,6 +488,9 @@ impl EditableNode {
             tangle::identifier_occurs_before(ident, id1, id2).unwrap()
         });
 
+        if !note.is_empty() {
+            ui.label(note.as_str());
+        }
         let resp = ui
             .horizontal(|ui| {
                 // let close = ui
@@ -422,14 +509,53 @@ impl EditableNode {
                 //     .inner;
                 // ui.separator();
                 let close = ui.button("⊗").clicked();
+                let split = ui
+                    .button("✂")
+                    .on_hover_text("split into one node per statement group")
+                    .clicked();
+                if let Some(err) = split_error {
+                    ui.colored_label(egui::Color32::RED, "⚠")
+                        .on_hover_text(err.as_str());
+                }
+                if !flow.is_empty() {
+                    ui.label(flow.join("."));
+                }
+                if !conflicts.is_empty() {
+                    let vars = conflicts
+                        .iter()
+                        .map(|v| v.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ");
+                    ui.colored_label(egui::Color32::YELLOW, "⚠")
+                        .on_hover_text(format!("also provided by another node: {}", vars));
+                }
+                if !mutations.is_empty() {
+                    let warnings = mutations
+                        .iter()
+                        .map(|m| m.to_string())
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    ui.colored_label(egui::Color32::from_rgb(255, 140, 0), "⚠")
+                        .on_hover_text(warnings);
+                }
                 *text_off = ui.min_rect().right_top().to_vec2() + egui::Vec2 { x: 10.0, y: 5.0 };
                 (
                     close,
+                    split,
                     crate::highlight::code_view_ui(ui, code, theme, exec, job),
                 )
             })
             .inner;
 
+        if let Some(defaults) = leaf.defaults_mut() {
+            for (param, value) in defaults.iter_mut() {
+                ui.horizontal(|ui| {
+                    ui.label(param.as_str());
+                    ui.text_edit_singleline(value);
+                });
+            }
+        }
+
         if let Some(prev) = last_output {
             Python::with_gil(|py| {
                 let pformat = py.import("pprint").unwrap().getattr("pformat").unwrap();
@@ -441,13 +567,17 @@ impl EditableNode {
             });
         }
 
-        (resp.0, resp.1.galley)
+        self.editing = resp.2.response.has_focus();
+        if resp.2.response.changed() {
+            self.split_error = None;
+        }
+        (resp.0, resp.1, resp.2.galley)
     }
 }
 
 impl TangleApp {
     fn bfs_ui(&self) -> Vec<Vec<String>> {
-        tangle::topo_sort(self.nodes.values().map(|n| &n.leaf).collect())
+        tangle::topo_layout(self.nodes.values().map(|n| &n.leaf).collect())
     }
 
     fn fresh_node(&mut self) {
@@ -467,12 +597,346 @@ impl TangleApp {
         self.last_id += 1;
     }
 
-    fn compile(&self) -> tangle::Tangle {
-        tangle::Tangle::from_leaves(
-            "dataflow".to_string(),
+    /// Parses code with the query pack in `dir` from now on, an empty `dir`
+    /// goes back to the built-in queries.
+    fn load_queries(&mut self, dir: &str) {
+        let pack = if dir.is_empty() {
+            Ok(queries::QueryPack::builtin())
+        } else {
+            queries::QueryPack::load(std::path::Path::new(dir))
+        };
+        self.query_report = match pack {
+            Ok(pack) => {
+                pack.activate();
+                self.query_pack = dir.to_string();
+                String::new()
+            }
+            Err(err) => err.to_string(),
+        };
+    }
+
+    fn load_flow(&mut self, name: &str) {
+        if let Some(flow) = self.flows.get(name) {
+            let nodes = flow
+                .leaves()
+                .into_iter()
+                .map(|(path, t)| {
+                    let mut node = EditableNode::new(t.name().as_str(), t.code().as_str());
+                    node.leaf.keep_annotations(t);
+                    node.leaf.keep_trivia(t);
+                    node.leaf.keep_defaults(t);
+                    node.id = t.id().clone();
+                    node.leaf.set_id(node.id.clone());
+                    node.flow = path;
+                    (t.name().clone(), node)
+                })
+                .collect();
+            self.nodes = nodes;
+            self.flow_name = name.to_string();
+            self.curr_module = None;
+            self.curr_flow = None;
+            self.bfs_layout = Some(self.bfs_ui());
+        }
+    }
+
+    /// Merges the edited flow with `other` into a new flow and edits that.
+    fn merge_flow(&mut self, other: &str) -> Result<(), tangle::Error> {
+        let flow = self.compile()?;
+        self.flows.insert(self.flow_name.clone(), flow.clone());
+        let mut merged = tangle::Tangle::merge(&flow, &self.flows[other])?;
+        let base = merged.name().clone();
+        let mut n = 1;
+        while self.flows.contains_key(merged.name()) {
+            n += 1;
+            merged.set_name(format!("{}_{}", base, n));
+        }
+        let name = merged.name().clone();
+        self.flows.insert(name.clone(), merged);
+        self.load_flow(&name);
+        Ok(())
+    }
+
+    /// Edits the flow of a graph `.json`, placing its leaves where the
+    /// document says when it places all of them. A flow of the same name is
+    /// kept, the imported one gets a fresh name.
+    fn import_graph(&mut self, text: &str) -> Result<(), String> {
+        let (mut flow, views) = interchange::import(text).map_err(|err| err.to_string())?;
+        if let Ok(edited) = self.compile() {
+            self.flows.insert(self.flow_name.clone(), edited);
+        }
+        let base = flow.name().clone();
+        let mut n = 1;
+        while self.flows.contains_key(flow.name()) || flow.name() == &self.flow_name {
+            n += 1;
+            flow.set_name(format!("{}_{}", base, n));
+        }
+        let name = flow.name().clone();
+        self.flows.insert(name.clone(), flow);
+        self.load_flow(&name);
+        let mut layout: Vec<Vec<(usize, String)>> = vec![];
+        let mut placed = true;
+        for (name, node) in self.nodes.iter_mut() {
+            let view = views.get(&node.id).cloned().unwrap_or_default();
+            node.note = view.note;
+            match view.position {
+                Some(pos) => {
+                    if layout.len() <= pos.row {
+                        layout.resize(pos.row + 1, vec![]);
+                    }
+                    layout[pos.row].push((pos.column, name.clone()));
+                }
+                None => placed = false,
+            }
+        }
+        if placed {
+            self.bfs_layout = Some(
+                layout
+                    .into_iter()
+                    .map(|mut row| {
+                        row.sort();
+                        row.into_iter().map(|(_, name)| name).collect()
+                    })
+                    .collect(),
+            );
+        }
+        self.end_note = String::new();
+        Ok(())
+    }
+
+    /// Writes the edited flow as a graph `.json`, with the position of each
+    /// leaf in the layout.
+    fn export_graph(&self, path: &str) -> Result<(), String> {
+        let flow = self.export_flow().map_err(|err| err.to_string())?;
+        let mut views: IndexMap<String, interchange::View> = self
+            .nodes
+            .values()
+            .map(|node| {
+                let view = interchange::View {
+                    position: None,
+                    note: node.note.clone(),
+                };
+                (node.id.clone(), view)
+            })
+            .collect();
+        for (row, names) in self.bfs_layout.iter().flatten().enumerate() {
+            for (column, name) in names.iter().enumerate() {
+                if let Some(node) = self.nodes.get(name) {
+                    views[&node.id].position = Some(interchange::Position { row, column });
+                }
+            }
+        }
+        let json = interchange::export(&flow, &views).map_err(|err| err.to_string())?;
+        std::fs::write(path, json).map_err(|err| err.to_string())
+    }
+
+    /// Replaces the edited nodes with the code cells of a notebook, either an
+    /// `.ipynb` or a `# %%` script.
+    fn import_notebook(&mut self, path: &str) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let imported = if path.ends_with(".py") {
+            notebook::import_percent(&text)
+        } else {
+            notebook::import_notebook(&text).map_err(|err| err.to_string())?
+        };
+        let name = std::path::Path::new(path)
+            .file_stem()
+            .map(|s| {
+                s.to_string_lossy()
+                    .replace(|c: char| !c.is_alphanumeric(), "_")
+            })
+            .unwrap_or_else(|| "notebook".to_string());
+        self.nodes = imported
+            .cells
+            .iter()
+            .map(|cell| {
+                let leaf = &cell.leaf;
+                let mut node = EditableNode::new(leaf.name().as_str(), leaf.code().as_str());
+                node.id = leaf.id().clone();
+                node.leaf.set_id(node.id.clone());
+                node.note = cell.markdown.clone();
+                (leaf.name().clone(), node)
+            })
+            .collect();
+        if let Ok(flow) = imported.flow(&name) {
+            self.flows.insert(name.clone(), flow);
+        }
+        self.end_note = imported.markdown.clone();
+        self.flow_name = name;
+        self.curr_module = None;
+        self.curr_flow = None;
+        self.bfs_layout = Some(self.bfs_ui());
+        self.import_report = imported
+            .unresolved
+            .iter()
+            .map(|u| u.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok(())
+    }
+
+    fn export_percent(&self, path: &str) -> Result<(), String> {
+        let cells: Vec<(&tangle::Tangle, &str)> = self
+            .export_nodes()?
+            .into_iter()
+            .map(|node| (&node.leaf, node.note.as_str()))
+            .collect();
+        let script =
+            notebook::export_percent(&cells, &self.end_note).map_err(|err| err.to_string())?;
+        std::fs::write(path, script).map_err(|err| err.to_string())
+    }
+
+    /// Writes the edited nodes as an `.ipynb`, with what each last returned
+    /// or raised.
+    fn export_ipynb(&self, path: &str) -> Result<(), String> {
+        let nodes = self.export_nodes()?;
+        let outputs: Vec<Option<notebook::Output>> = Python::with_gil(|py| {
+            let pformat = py.import("pprint")?.getattr("pformat")?;
+            let format_exception = py.import("traceback")?.getattr("format_exception")?;
+            nodes
+                .iter()
+                .map(|node| {
+                    if let Some(exn) = &node.last_output {
+                        let exn = exn.as_ref(py);
+                        let ty = exn.get_type();
+                        let traceback: Vec<String> = format_exception
+                            .call1((ty, exn, exn.getattr("__traceback__")?))?
+                            .extract()?;
+                        Ok(Some(notebook::Output::Error {
+                            ename: ty.name()?.to_string(),
+                            evalue: exn.str()?.to_string(),
+                            traceback: traceback
+                                .iter()
+                                .map(|l| l.trim_end_matches('\n').to_string())
+                                .collect(),
+                        }))
+                    } else if let Some(ret) = &node.last_result {
+                        let repr = pformat.call1((ret.as_ref(py),))?.str()?;
+                        Ok(Some(notebook::Output::Result(repr.to_string())))
+                    } else {
+                        Ok(None)
+                    }
+                })
+                .collect::<PyResult<_>>()
+        })
+        .map_err(|err| err.to_string())?;
+        let cells: Vec<(&tangle::Tangle, &str, Option<&notebook::Output>)> = nodes
+            .iter()
+            .zip(&outputs)
+            .map(|(node, out)| (&node.leaf, node.note.as_str(), out.as_ref()))
+            .collect();
+        let json =
+            notebook::export_notebook(&cells, &self.end_note).map_err(|err| err.to_string())?;
+        std::fs::write(path, json).map_err(|err| err.to_string())
+    }
+
+    /// Writes the edited flow as a script that runs without tangle.
+    fn export_script(&self, path: &str) -> Result<(), String> {
+        let flow = self.export_flow().map_err(|err| err.to_string())?;
+        let script = flow
+            .emit_script(self.async_script)
+            .map_err(|err| err.to_string())?;
+        std::fs::write(path, script).map_err(|err| err.to_string())
+    }
+
+    /// Replaces a node with the leaves `Tangle::split_script` finds in its code,
+    /// named `{name}_1`, `{name}_2`, .. skipping the names of other nodes.
+    fn split_node(&mut self, name: &str) -> Result<(), tangle::Error> {
+        let node = &self.nodes[name];
+        let split = tangle::Tangle::split_script(name.to_string(), &node.code)?;
+        let path = node.flow.clone();
+        let leaves = split.leaves();
+        let fresh: Vec<String> = (1..)
+            .map(|k| format!("{}_{}", name, k))
+            .filter(|leaf_name| !self.nodes.contains_key(leaf_name))
+            .take(leaves.len())
+            .collect();
+        let nodes = std::mem::take(&mut self.nodes);
+        for (old_name, old) in nodes {
+            if old_name != name {
+                self.nodes.insert(old_name, old);
+                continue;
+            }
+            for ((_, leaf), leaf_name) in leaves.iter().zip(&fresh) {
+                let mut node = EditableNode::new(leaf_name, leaf.code().as_str());
+                node.flow = path.clone();
+                self.nodes.insert(leaf_name.clone(), node);
+            }
+        }
+        self.bfs_layout = Some(self.bfs_ui());
+        Ok(())
+    }
+
+    /// Changes whenever the flow `compile` gives could, to know when what is
+    /// derived from it is stale.
+    fn revision(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.flow_name.hash(&mut hasher);
+        for (name, node) in &self.nodes {
+            let leaf = &node.leaf;
+            let (requires, provides) = leaf.deps();
+            (name, &node.flow, leaf.id(), leaf.code()).hash(&mut hasher);
+            requires.iter().collect::<Vec<_>>().hash(&mut hasher);
+            provides.iter().collect::<Vec<_>>().hash(&mut hasher);
+            leaf.defaults().iter().collect::<Vec<_>>().hash(&mut hasher);
+            leaf.annotations()
+                .iter()
+                .collect::<Vec<_>>()
+                .hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// `compile`, redone only when the nodes changed.
+    fn compiled(&mut self) -> Result<&tangle::Tangle, &str> {
+        let revision = self.revision();
+        if self.compiled.as_ref().map(|(at, _)| *at) != Some(revision) {
+            let flow = self.compile().map_err(|err| err.to_string());
+            self.compiled = Some((revision, flow));
+        }
+        match &self.compiled.as_ref().unwrap().1 {
+            Ok(flow) => Ok(flow),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The flow exports write: the edited flow, sliced down to `slice_vars`
+    /// unless that is empty.
+    fn export_flow(&self) -> Result<tangle::Tangle, tangle::Error> {
+        let flow = self.compile()?;
+        let vars = self
+            .slice_vars
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>();
+        if vars.is_empty() {
+            Ok(flow)
+        } else {
+            flow.slice_for(&vars)
+        }
+    }
+
+    /// The nodes of `export_flow`, in editor order.
+    fn export_nodes(&self) -> Result<Vec<&EditableNode>, String> {
+        let flow = self.export_flow().map_err(|err| err.to_string())?;
+        let leaves = flow
+            .leaves()
+            .into_iter()
+            .map(|(_, leaf)| leaf.name().clone())
+            .collect::<HashSet<_>>();
+        Ok(self
+            .nodes
+            .values()
+            .filter(|node| leaves.contains(node.leaf.name()))
+            .collect())
+    }
+
+    fn compile(&self) -> Result<tangle::Tangle, tangle::Error> {
+        tangle::Tangle::from_paths(
+            self.flow_name.clone(),
             self.nodes
                 .values()
-                .map(|n| (n.name.clone(), n.leaf.clone()))
+                .map(|n| (n.flow.clone(), n.leaf.clone()))
                 .collect(),
         )
     }
@@ -482,14 +946,22 @@ impl TangleApp {
             if let tangle::Tangle::Leaf { code, .. } = &node.leaf {
                 if &node.code != code {
                     match tangle::Tangle::from_cell(name.clone(), &node.code) {
-                        Err((exn, (partial_leaf, ident))) => Python::with_gil(|py| {
-                            node.last_output = Some(exn.pvalue(py).into_py(py))
-                        }),
-                        Ok((leaf, ident)) => {
+                        Err((err, (partial_leaf, ident))) => {
+                            let exn = cell_error(&err, &node.code, name);
+                            Python::with_gil(|py| {
+                                node.last_output = Some(exn.pvalue(py).into_py(py))
+                            })
+                        }
+                        Ok((mut leaf, ident)) => {
+                            leaf.keep_annotations(&node.leaf);
+                            leaf.keep_trivia(&node.leaf);
+                            leaf.keep_defaults(&node.leaf);
+                            leaf.set_id(node.id.clone());
                             node.ident = ident;
                             node.code = leaf.code().clone();
                             node.leaf = leaf;
                             node.last_output = None;
+                            node.last_result = None;
                         }
                     }
                 }
@@ -499,22 +971,30 @@ impl TangleApp {
         }
     }
 
-    fn compile_fresh_flow(&self) -> (tangle::Tangle, PyResult<Py<PyModule>>) {
-        let flow = self.compile();
-        let code = flow.emit_decorated(&Some("@tangle.pyapi.memo"));
+    fn compile_fresh_flow(
+        &self,
+    ) -> Result<(tangle::Tangle, PyResult<Py<PyModule>>), tangle::Error> {
+        let flow = self.compile()?;
+        let code = flow.emit_decorated(&Some("@tangle.pyapi.memo"))?;
         let full_module = format!("import tangle.pyapi\r\n\r\nprint('hey')\r\n\r\n{}", code);
-        (
+        Ok((
             flow,
             Python::with_gil(|py| {
                 pyo3::types::PyModule::from_code(py, full_module.as_str(), "<dataflow>", "dataflow")
                     .map(|m| m.into_py(py))
             }),
-        )
+        ))
     }
 
     fn compile_module(&mut self) -> PyResult<()> {
         self.compile_cells();
-        let (flow, new_module) = self.compile_fresh_flow();
+        let (flow, new_module) = match self.compile_fresh_flow() {
+            Ok(compiled) => compiled,
+            Err(err) => {
+                self.bfs_layout = Some(self.bfs_ui());
+                return Err(pyo3::exceptions::PyValueError::new_err(err.to_string()));
+            }
+        };
         self.curr_module = Some(new_module?);
         self.curr_flow = Some(flow);
         self.bfs_layout = Some(self.bfs_ui());
@@ -533,7 +1013,7 @@ impl TangleApp {
                 let flow = curr_flow.as_ref().unwrap();
                 let (requires, provides) = flow.deps();
                 let flow_mod = flow_mod_py.as_ref(py);
-                let pyflow = flow_mod.getattr("dataflow")?;
+                let pyflow = flow_mod.getattr(flow.name().as_str())?;
                 let pyapi = py.import("tangle.pyapi")?;
                 let set_gui_ref = pyapi.getattr("set_gui_ref")?;
                 let ui_wrapper = pyapi.getattr("UiWrapper")?;
@@ -588,34 +1068,43 @@ impl epi::App for TangleApp {
             }
         }
 
+        let config = std::env::var("XDG_CONFIG_HOME")
+            .map(std::path::PathBuf::from)
+            .or_else(|_| {
+                std::env::var("HOME").map(|home| std::path::Path::new(&home).join(".config"))
+            })
+            .map(|config| config.join("tangle").join("queries"));
+        let pack = match std::env::var("TANGLE_QUERIES") {
+            Ok(dir) => Some(dir),
+            Err(_) if !self.query_pack.is_empty() => Some(self.query_pack.clone()),
+            Err(_) => config
+                .ok()
+                .filter(|dir| dir.is_dir())
+                .map(|dir| dir.display().to_string()),
+        };
+        if let Some(dir) = pack {
+            self.load_queries(&dir);
+        }
+
         let tst = include_str!("test_flow.py");
-        let tangle = tangle::Tangle::from_code(tst.to_string());
-        if let Ok(tangle) = tangle {
-            // println!("{}", &tangle.emit_all());
-            self.nodes.clear();
-            self.bfs_layout.take();
-            match tangle {
-                tangle::Tangle::Dataflow {
-                    nodes,
-                    requires,
-                    provides,
-                    ..
-                } => {
-                    self.nodes = nodes
-                        .into_iter()
-                        .map(|(name, t)| {
-                            (
-                                name.clone(),
-                                EditableNode::new(name.as_str(), t.code().as_str()),
-                            )
-                        })
-                        .collect();
-                    self.bfs_layout = Some(self.bfs_ui());
-                }
-                _ => {}
+        match tangle::Tangle::from_code(tst.to_string()) {
+            Ok(parsed) => {
+                self.flows = parsed.flows;
+                self.flow_report = parsed
+                    .errors
+                    .iter()
+                    .map(|err| err.render(tst, "test_flow.py"))
+                    .chain(parsed.warnings.iter().map(|w| format!("warning: {}", w)))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let name = if self.flows.contains_key("dataflow") {
+                    "dataflow".to_string()
+                } else {
+                    self.flows.keys().next().unwrap().clone()
+                };
+                self.load_flow(&name);
             }
-        } else {
-            panic!();
+            Err(err) => panic!("{}", err.render(tst, "test_flow.py")),
         }
 
         ctx.set_style(self.style.clone());
@@ -624,6 +1113,10 @@ impl epi::App for TangleApp {
     }
 
     fn save(&mut self, storage: &mut dyn epi::Storage) {
+        if let Ok(flow) = self.compile() {
+            self.saved_flow = Some(flow);
+            self.diff_text = None;
+        }
         epi::set_value(storage, "me", self);
         epi::set_value(storage, "theme", &self.syntax_theme);
     }
@@ -651,21 +1144,63 @@ impl epi::App for TangleApp {
         }
 
         let mut remove_at = None;
+        let mut split_at = None;
         let theme = &self.syntax_theme.clone();
         let mut any_empty = false;
+        let conflicts = tangle::find_conflicts(self.nodes.values().map(|n| &n.leaf).collect());
+        let revision = self.revision();
+        if self.mutations.as_ref().map(|(at, _)| *at) != Some(revision) {
+            let leaves = self.nodes.values().map(|n| &n.leaf).collect();
+            self.mutations = Some((revision, tangle::find_mutations(leaves)));
+        }
+        let mutations = self.mutations.as_ref().unwrap().1.clone();
+        let editing = self
+            .nodes
+            .values()
+            .find(|n| n.editing)
+            .map(|n| n.name.clone());
+        let impacted = match editing {
+            Some(editing) => match self.compiled() {
+                Ok(flow) => flow.impact_of(&editing),
+                // show what can be worked out while the flow doesn't compile
+                Err(_) => {
+                    tangle::downstream(self.nodes.values().map(|n| &n.leaf).collect(), &editing)
+                }
+            },
+            None => Default::default(),
+        };
         if let Some(layout) = &self.bfs_layout {
             let mut row_off = egui::Pos2::ZERO + egui::Vec2::new(120.0, 120.0);
             for row in layout {
                 let mut col_off = egui::Rect::from_min_max(row_off, row_off);
                 for col in row {
                     if let Some(node) = self.nodes.get_mut(col) {
-                        let window = egui::Window::new(col)
+                        let node_conflicts = conflicts
+                            .iter()
+                            .filter(|(_, nodes)| nodes.contains(col))
+                            .map(|(var, _)| var)
+                            .collect::<Vec<&String>>();
+                        let node_mutations = mutations
+                            .iter()
+                            .filter(|m| &m.consumer == col)
+                            .collect::<Vec<&tangle::Mutation>>();
+                        let mut window = egui::Window::new(col)
+                            .id(egui::Id::new(&node.id))
                             .title_bar(false)
-                            .current_pos(col_off.right_top())
-                            .show(ctx, |ui| node.ui(ui, theme, exec))
+                            .current_pos(col_off.right_top());
+                        if impacted.contains(col) {
+                            // outputs of this node will be invalidated by the edit
+                            let mut frame = egui::Frame::window(&ctx.style());
+                            frame.stroke = egui::Stroke::new(2.0, egui::Color32::GOLD);
+                            window = window.frame(frame);
+                        }
+                        let window = window
+                            .show(ctx, |ui| {
+                                node.ui(ui, theme, exec, &node_conflicts, &node_mutations)
+                            })
                             .unwrap();
 
-                        let (close, galley) = window.inner.unwrap();
+                        let (close, split, galley) = window.inner.unwrap();
                         col_off = col_off.union(window.response.rect);
                         col_off.extend_with_x(col_off.right() + 14.0);
                         if let Some(dbg_rect) = node.debug_output_rect {
@@ -674,6 +1209,9 @@ impl epi::App for TangleApp {
                         if close {
                             remove_at = Some(col.clone());
                         }
+                        if split {
+                            split_at = Some(col.clone());
+                        }
                         any_empty |= node.code.len() == 0;
                         node.changed_output.clear();
                         node.response = Some(window.response);
@@ -699,7 +1237,8 @@ impl epi::App for TangleApp {
                     .curr_flow
                     .as_ref()
                     .unwrap()
-                    .emit_decorated(&Some("@tangle.pyapi.memo"));
+                    .emit_decorated(&Some("@tangle.pyapi.memo"))
+                    .unwrap_or_else(|err| err.to_string());
                 ui.add(egui::widgets::TextEdit::multiline(&mut code).interactive(false));
             });
         }
@@ -713,6 +1252,228 @@ impl epi::App for TangleApp {
             self.nodes.remove(&at);
             self.bfs_layout = Some(self.bfs_ui());
         }
+        if let Some(at) = split_at {
+            if let Err(err) = self.split_node(&at) {
+                let node = &mut self.nodes[&at];
+                node.split_error = Some(err.render(&node.code, &at));
+            }
+        }
+
+        let mut load = None;
+        let mut merge = None;
+        egui::Window::new("flows").show(ctx, |ui| {
+            for name in self.flows.keys() {
+                ui.horizontal(|ui| {
+                    if ui
+                        .selectable_label(name == &self.flow_name, name.as_str())
+                        .clicked()
+                    {
+                        load = Some(name.clone());
+                    }
+                    if name != &self.flow_name
+                        && ui
+                            .small_button("merge")
+                            .on_hover_text("merge with the edited flow into a new flow")
+                            .clicked()
+                    {
+                        merge = Some(name.clone());
+                    }
+                });
+            }
+            if !self.flow_report.is_empty() {
+                ui.colored_label(egui::Color32::YELLOW, self.flow_report.as_str());
+            }
+        });
+        if let Some(name) = merge {
+            self.flow_report = match self.merge_flow(&name) {
+                Ok(()) => String::new(),
+                Err(err) => err.to_string(),
+            };
+        }
+        if let Some(name) = load {
+            // keep edits to the flow we are switching away from, stay on it
+            // until they compile
+            self.flow_report = match self.compile() {
+                Ok(flow) => {
+                    self.flows.insert(self.flow_name.clone(), flow);
+                    self.load_flow(&name);
+                    String::new()
+                }
+                Err(err) => format!("can't leave `{}`: {}", self.flow_name, err),
+            };
+        }
+
+        let mut import = false;
+        let mut export = false;
+        let mut export_script = false;
+        let mut export_ipynb = false;
+        egui::Window::new("import notebook").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.import_path);
+                import = ui.button("import .ipynb / # %%").clicked();
+                export = ui.button("export # %%").clicked();
+                export_ipynb = ui.button("export .ipynb").clicked();
+                export_script = ui.button("export script").clicked();
+                ui.checkbox(&mut self.async_script, "async")
+                    .on_hover_text("run the nodes of each level with asyncio.gather");
+            });
+            ui.horizontal(|ui| {
+                ui.label("only for");
+                ui.text_edit_singleline(&mut self.slice_vars).on_hover_text(
+                    "export only the nodes computing these variables, all nodes if empty",
+                );
+            });
+            if !self.import_report.is_empty() {
+                ui.colored_label(egui::Color32::YELLOW, self.import_report.as_str());
+            }
+        });
+        if import {
+            let path = self.import_path.clone();
+            if let Err(err) = self.import_notebook(&path) {
+                self.import_report = err;
+            }
+        }
+        if export {
+            let path = self.import_path.clone();
+            self.import_report = match self.export_percent(&path) {
+                Ok(()) => format!("exported to {}", path),
+                Err(err) => err,
+            };
+        }
+        if export_ipynb {
+            let path = self.import_path.clone();
+            self.import_report = match self.export_ipynb(&path) {
+                Ok(()) => format!("exported to {}", path),
+                Err(err) => err,
+            };
+        }
+
+        let mut load_queries = false;
+        egui::Window::new("query pack").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.query_pack);
+                load_queries = ui
+                    .button("load")
+                    .on_hover_text(
+                        "query.scm, node_query.scm and cell_query.scm, empty for the built-in ones",
+                    )
+                    .clicked();
+            });
+            if !self.query_report.is_empty() {
+                ui.colored_label(egui::Color32::YELLOW, self.query_report.as_str());
+            }
+        });
+        if load_queries {
+            let dir = self.query_pack.clone();
+            self.load_queries(&dir);
+        }
+
+        if export_script {
+            let path = self.import_path.clone();
+            self.import_report = match self.export_script(&path) {
+                Ok(()) => format!("exported to {}", path),
+                Err(err) => err,
+            };
+        }
+
+        let mut import_graph = false;
+        let mut export_graph = false;
+        let mut validate_graph = false;
+        egui::Window::new("graph").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.graph_path);
+                import_graph = ui.button("import .json").clicked();
+                export_graph = ui.button("export .json").clicked();
+                validate_graph = ui.button("validate .json").clicked();
+                if ui.button("copy schema").clicked() {
+                    ui.output().copied_text = interchange::SCHEMA.to_string();
+                }
+            });
+            ui.horizontal(|ui| {
+                let dot = ui.button("copy DOT").clicked();
+                let mermaid = ui.button("copy Mermaid").clicked();
+                if dot || mermaid {
+                    let graph = self.export_flow().and_then(|flow| {
+                        if dot {
+                            flow.to_dot()
+                        } else {
+                            flow.to_mermaid()
+                        }
+                    });
+                    self.graph_report = match graph {
+                        Ok(graph) => {
+                            ui.output().copied_text = graph;
+                            String::new()
+                        }
+                        Err(err) => err.to_string(),
+                    };
+                }
+            });
+            if !self.graph_report.is_empty() {
+                ui.colored_label(egui::Color32::YELLOW, self.graph_report.as_str());
+            }
+        });
+        if import_graph {
+            let path = self.graph_path.clone();
+            self.graph_report = match std::fs::read_to_string(&path) {
+                Ok(text) => match self.import_graph(&text) {
+                    Ok(()) => format!("imported `{}`", self.flow_name),
+                    Err(err) => err,
+                },
+                Err(err) => err.to_string(),
+            };
+        }
+        if validate_graph {
+            let path = self.graph_path.clone();
+            self.graph_report = match std::fs::read_to_string(&path) {
+                Ok(text) => match interchange::validate(&text) {
+                    Ok(()) => format!("{} is a valid tangle graph", path),
+                    Err(err) => err.to_string(),
+                },
+                Err(err) => err.to_string(),
+            };
+        }
+        if export_graph {
+            let path = self.graph_path.clone();
+            self.graph_report = match self.export_graph(&path) {
+                Ok(()) => format!("exported to {}", path),
+                Err(err) => err,
+            };
+        }
+
+        if let Some(saved) = &self.saved_flow {
+            let revision = self.revision();
+            let stale = match &self.diff_text {
+                Some((at, as_json, _)) => *at != revision || *as_json != self.diff_as_json,
+                None => true,
+            };
+            if stale {
+                let text = match self.compile() {
+                    Ok(flow) => {
+                        let diff = saved.diff(&flow);
+                        if self.diff_as_json {
+                            diff.to_json()
+                        } else if diff.is_empty() {
+                            "no changes".to_string()
+                        } else {
+                            diff.to_string()
+                        }
+                    }
+                    Err(err) => err.to_string(),
+                };
+                self.diff_text = Some((revision, self.diff_as_json, text));
+            }
+            let as_json = &mut self.diff_as_json;
+            let mut text = self.diff_text.as_ref().unwrap().2.clone();
+            egui::Window::new("diff since save").show(ctx, |ui| {
+                ui.checkbox(as_json, "json");
+                ui.add(
+                    egui::TextEdit::multiline(&mut text)
+                        .code_editor()
+                        .interactive(false),
+                );
+            });
+        }
 
         egui::Window::new("theme edit").show(ctx, |ui| {
             ctx.style_ui(ui);
@@ -729,6 +1490,9 @@ impl epi::App for TangleApp {
 
         if let Some(layout) = &self.bfs_layout {
             let painter = ctx.layer_painter(egui::LayerId::background());
+            let cycles = tangle::find_cycles(self.nodes.values().map(|n| &n.leaf).collect());
+            let mismatches =
+                tangle::find_type_mismatches(self.nodes.values().map(|n| &n.leaf).collect());
             for level in layout {
                 for node in level.iter().filter_map(|n| self.nodes.get(n)) {
                     let (requires, provides) = node.leaf.deps();
@@ -752,9 +1516,34 @@ impl epi::App for TangleApp {
                             .duration_since(std::time::SystemTime::UNIX_EPOCH)
                             .unwrap();
                         let s = time.as_secs_f64() % 10.0;
-                        let colour: egui::Color32 = {
+                        let in_cycle = cycles.iter().any(|c| {
+                            c.nodes.contains(&node.name)
+                                && c.nodes.contains(&dep.name)
+                                && c.vars.contains(dep_var)
+                        });
+                        let mismatch = mismatches.iter().find_map(|err| match err.kind() {
+                            tangle::ErrorKind::TypeMismatch {
+                                var,
+                                provider,
+                                provided,
+                                consumer,
+                                required,
+                                ..
+                            } if *provider == node.name
+                                && *consumer == dep.name
+                                && var == dep_var =>
+                            {
+                                Some((provided, required))
+                            }
+                            _ => None,
+                        });
+                        let colour: egui::Color32 = if in_cycle {
+                            egui::Rgba::from(egui::Color32::RED)
+                        } else if mismatch.is_some() {
+                            egui::Rgba::from(egui::Color32::from_rgb(255, 140, 0))
+                        } else {
                             soff = ctx.animate_bool(
-                                egui::Id::new(&node.name).with(dep_var),
+                                egui::Id::new(&node.id).with(dep_var),
                                 node.changed_output.contains(dep_var),
                             );
                             soff = soff.sqrt();
@@ -798,6 +1587,15 @@ impl epi::App for TangleApp {
                                     egui::Stroke::new(2.0 + 2.0 * w, colour.additive()),
                                 );
                             }
+                            if let Some((provided, required)) = mismatch {
+                                painter.text(
+                                    (node.text_off + start).to_pos2() + to / 2.0,
+                                    egui::Align2::CENTER_BOTTOM,
+                                    format!("{} ≠ {}", provided, required),
+                                    egui::TextStyle::Small,
+                                    colour,
+                                );
+                            }
                             // painter.line_segment(
                             //     [
                             //         node.text_off.to_pos2() + start,
@@ -832,6 +1630,23 @@ fn gui() {
         focused: 0,
         last_id: 0,
         bfs_layout: None,
+        flow_name: "dataflow".to_string(),
+        flows: IndexMap::new(),
+        flow_report: String::new(),
+        saved_flow: None,
+        diff_as_json: false,
+        diff_text: None,
+        compiled: None,
+        mutations: None,
+        import_path: String::new(),
+        end_note: String::new(),
+        async_script: false,
+        slice_vars: String::new(),
+        import_report: String::new(),
+        graph_path: String::new(),
+        graph_report: String::new(),
+        query_pack: String::new(),
+        query_report: String::new(),
         curr_module: None,
         curr_flow: None,
     };
@@ -841,10 +1656,46 @@ fn gui() {
     eframe::run_native(Box::new(app), native_options);
 }
 /// A Python module implemented in Rust.
+/// Checks a graph `.json`, raising `ValueError` with every place it breaks
+/// the schema.
+#[pyfunction]
+fn validate_graph(json: &str) -> PyResult<()> {
+    interchange::validate(json)
+        .map_err(|err| pyo3::exceptions::PyValueError::new_err(err.render(json, "graph.json")))
+}
+
+/// The Python code of the flow in a graph `.json`.
+#[pyfunction]
+fn import_graph(json: &str) -> PyResult<String> {
+    let to_py = |err: tangle::Error| {
+        pyo3::exceptions::PyValueError::new_err(err.render(json, "graph.json"))
+    };
+    let (flow, _) = interchange::import(json).map_err(to_py)?;
+    flow.emit_all()
+        .map_err(|err| pyo3::exceptions::PyValueError::new_err(err.to_string()))
+}
+
+/// The flow `name` of Python `code` as a graph `.json`.
+#[pyfunction]
+fn export_graph(code: &str, name: &str) -> PyResult<String> {
+    let to_py =
+        |err: tangle::Error| pyo3::exceptions::PyValueError::new_err(err.render(code, "<code>"));
+    let mut flows = tangle::Tangle::from_code(code.to_string())
+        .map_err(to_py)?
+        .flows;
+    let flow = flows.remove(name).ok_or_else(|| {
+        pyo3::exceptions::PyKeyError::new_err(format!("no flow `{}` in the code", name))
+    })?;
+    interchange::export(&flow, &Default::default()).map_err(to_py)
+}
+
 #[pymodule]
-fn tangle(_py: Python, _m: &PyModule) -> PyResult<()> {
+fn tangle(_py: Python, m: &PyModule) -> PyResult<()> {
     // pyo3::prepare_freethreaded_python();
     // println!("{:?}", pyapi.getattr("compile_extract").call1());
+    m.add_function(wrap_pyfunction!(validate_graph, m)?)?;
+    m.add_function(wrap_pyfunction!(import_graph, m)?)?;
+    m.add_function(wrap_pyfunction!(export_graph, m)?)?;
     gui();
     Ok(())
 }
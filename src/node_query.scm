@@ -1,2 +1,2 @@
-(assignment left: [(list_pattern) (identifier)] @flow.node.provides 
-          right: (call function: (identifier) @flow.node.name arguments: (argument_list) @flow.node.param))
\ No newline at end of file
+(assignment left: [(list_pattern) (pattern_list) (tuple_pattern) (identifier)] @flow.node.provides 
+          right: (call function: (identifier) @flow.node.name arguments: (argument_list) @flow.node.param))
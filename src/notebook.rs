@@ -0,0 +1,733 @@
+use std::ops::Range;
+
+use crate::scope::{self, Access, Use};
+use crate::tangle::{fresh_id, Error, IndexMap, IndexSet, Tangle, Trivia};
+
+#[derive(serde::Deserialize)]
+struct Notebook {
+    cells: Vec<Cell>,
+}
+
+#[derive(serde::Deserialize)]
+struct Cell {
+    cell_type: String,
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    metadata: CellMetadata,
+    source: Source,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct CellMetadata {
+    #[serde(default)]
+    tangle: Option<LeafMetadata>,
+}
+
+/// What `export_notebook` records about the leaf of a cell.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct LeafMetadata {
+    name: String,
+    requires: IndexSet<String>,
+    provides: IndexSet<String>,
+    /// assigned at the top of the cell, so it runs in Jupyter
+    #[serde(default)]
+    defaults: IndexMap<String, String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    annotations: IndexMap<String, String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    trivia: Trivia,
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Source {
+    Lines(Vec<String>),
+    Text(String),
+}
+
+impl Source {
+    fn text(self) -> String {
+        match self {
+            Source::Lines(lines) => lines.concat(),
+            Source::Text(text) => text,
+        }
+    }
+}
+
+pub struct ImportedCell {
+    pub leaf: Tangle,
+    /// markdown cells between the previous code cell and this one
+    pub markdown: String,
+}
+
+/// A cross-cell reassignment the importer could not turn into a new variable.
+#[derive(Debug, Clone)]
+pub struct Unresolved {
+    pub var: String,
+    pub cell: String,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` in {} is reassigned {}, later cells still read the earlier value",
+            self.var, self.cell, self.reason
+        )
+    }
+}
+
+pub struct ImportedNotebook {
+    pub cells: Vec<ImportedCell>,
+    /// markdown cells after the last code cell
+    pub markdown: String,
+    pub unresolved: Vec<Unresolved>,
+}
+
+impl ImportedNotebook {
+    pub fn flow(&self, name: &str) -> Result<Tangle, Error> {
+        Tangle::from_leaves(
+            name.to_string(),
+            self.cells
+                .iter()
+                .map(|c| (c.leaf.name().clone(), c.leaf.clone()))
+                .collect(),
+        )
+    }
+}
+
+/// IPython magics and shell escapes are not Python, keep them as comments.
+fn comment_magics(code: &str) -> String {
+    code.lines()
+        .map(|l| {
+            let trimmed = l.trim_start();
+            if trimmed.starts_with('%') || trimmed.starts_with('!') {
+                format!("# {}", l)
+            } else {
+                l.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+enum RawCell {
+    Code {
+        name: Option<String>,
+        id: Option<String>,
+        code: String,
+    },
+    Markdown(String),
+}
+
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Turns the code cells of a notebook into leaves named `cell_1`, `cell_2`, ..
+/// in notebook order. A variable reassigned by a later cell becomes a new
+/// variable `x_1`, `x_2`, .. which later cells read instead, so the cells form
+/// a valid flow. Reassignments that can't be renamed this way are reported in
+/// `unresolved` and keep the old name.
+pub fn import_notebook(json: &str) -> serde_json::Result<ImportedNotebook> {
+    let notebook: Notebook = serde_json::from_str(json)?;
+    let mut code_cells = notebook.cells.iter().filter(|c| c.cell_type == "code");
+    if code_cells.all(|c| c.metadata.tangle.is_some()) {
+        return Ok(import_exported(notebook.cells));
+    }
+    let raw = notebook
+        .cells
+        .into_iter()
+        .filter_map(|cell| match cell.cell_type.as_str() {
+            "code" => Some(RawCell::Code {
+                name: cell.metadata.tangle.map(|leaf| leaf.name),
+                id: cell.id,
+                code: cell.source.text(),
+            }),
+            "markdown" => Some(RawCell::Markdown(cell.source.text())),
+            _ => None,
+        })
+        .collect();
+    Ok(import_cells(raw))
+}
+
+/// Reads back the leaves of a notebook `export_notebook` wrote, as they were.
+fn import_exported(notebook: Vec<Cell>) -> ImportedNotebook {
+    let mut cells: Vec<ImportedCell> = vec![];
+    let mut markdown: Vec<String> = vec![];
+    for cell in notebook {
+        match (cell.cell_type.as_str(), cell.metadata.tangle) {
+            ("code", Some(mut meta)) => {
+                let mut code = cell.source.text();
+                // values edited in Jupyter become the new defaults
+                for (param, value) in meta.defaults.iter_mut() {
+                    let assigned = format!("{} = ", param);
+                    let (line, rest) = code.split_once('\n').unwrap_or((&code, ""));
+                    if let Some(edited) = line.strip_prefix(&assigned) {
+                        *value = edited.trim().to_string();
+                        code = rest.to_string();
+                    }
+                }
+                let leaf = Tangle::Leaf {
+                    name: meta.name,
+                    provides: meta.provides,
+                    requires: meta.requires,
+                    code,
+                    annotations: meta.annotations,
+                    id: cell.id.unwrap_or_else(fresh_id),
+                    defaults: meta.defaults,
+                    trivia: meta.trivia,
+                };
+                cells.push(ImportedCell {
+                    leaf,
+                    markdown: std::mem::take(&mut markdown).join("\n\n"),
+                });
+            }
+            ("markdown", _) => markdown.push(cell.source.text()),
+            _ => {}
+        }
+    }
+    ImportedNotebook {
+        cells,
+        markdown: markdown.join("\n\n"),
+        unresolved: vec![],
+    }
+}
+
+/// The `source` of a notebook cell, lines keeping their `\n`.
+fn source_lines(text: &str) -> Vec<String> {
+    let lines: Vec<&str> = text.lines().map(|l| l.trim_end_matches('\r')).collect();
+    let last = lines.len().saturating_sub(1);
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, l)| {
+            if i < last {
+                format!("{}\n", l)
+            } else {
+                l.to_string()
+            }
+        })
+        .collect()
+}
+
+/// The cached output of a cell, see `export_notebook`.
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// text of the value the cell returned
+    Result(String),
+    /// the exception the cell raised
+    Error {
+        ename: String,
+        evalue: String,
+        traceback: Vec<String>,
+    },
+}
+
+/// A leaf or sub-flow to export, with the markdown above it and its output.
+type ExportedCell<'c, T> = (&'c Tangle, &'c str, Option<T>);
+
+/// The leaves of `cells` in `topo_sort` order, sub-flows flattened into their
+/// leaves. The markdown of a sub-flow goes with its first leaf, its output
+/// with its last.
+fn in_flow_order<'c, T: Copy>(
+    cells: &[ExportedCell<'c, T>],
+) -> Result<Vec<ExportedCell<'c, T>>, Error> {
+    let mut leaves: IndexMap<&String, ExportedCell<T>> = IndexMap::new();
+    for (cell, markdown, output) in cells {
+        let inner = cell.leaves();
+        let last = inner.len().saturating_sub(1);
+        for (i, (_, leaf)) in inner.into_iter().enumerate() {
+            let markdown = if i == 0 { *markdown } else { "" };
+            leaves.insert(leaf.name(), (leaf, markdown, output.filter(|_| i == last)));
+        }
+    }
+    let order = crate::tangle::topo_sort(leaves.values().map(|(leaf, _, _)| *leaf).collect())?;
+    Ok(order.iter().flatten().map(|name| leaves[name]).collect())
+}
+
+/// Writes leaves as an `.ipynb`, one code cell each in `topo_sort` order,
+/// after their markdown and with their cached output, if any, followed by
+/// the markdown `end`. The cell metadata keeps what `import_notebook` needs
+/// to rebuild the graph.
+pub fn export_notebook(
+    cells: &[(&Tangle, &str, Option<&Output>)],
+    end: &str,
+) -> Result<String, Error> {
+    let mut json = vec![];
+    for (leaf, markdown, output) in in_flow_order(cells)? {
+        if !markdown.is_empty() {
+            json.push(serde_json::json!({
+                "cell_type": "markdown",
+                "id": format!("{}-md", leaf.id()),
+                "metadata": {},
+                "source": source_lines(markdown),
+            }));
+        }
+        let (requires, provides) = leaf.deps();
+        let meta = LeafMetadata {
+            name: leaf.name().clone(),
+            requires: requires.clone(),
+            provides: provides.clone(),
+            defaults: leaf.defaults(),
+            annotations: leaf.annotations().clone(),
+            trivia: leaf.trivia().clone(),
+        };
+        let mut code: String = meta
+            .defaults
+            .iter()
+            .map(|(param, value)| format!("{} = {}\n", param, value))
+            .collect();
+        code.push_str(leaf.code());
+        let outputs = match output {
+            Some(Output::Result(text)) => vec![serde_json::json!({
+                "output_type": "execute_result",
+                "execution_count": null,
+                "metadata": {},
+                "data": { "text/plain": source_lines(text) },
+            })],
+            Some(Output::Error {
+                ename,
+                evalue,
+                traceback,
+            }) => vec![serde_json::json!({
+                "output_type": "error",
+                "ename": ename,
+                "evalue": evalue,
+                "traceback": traceback,
+            })],
+            None => vec![],
+        };
+        json.push(serde_json::json!({
+            "cell_type": "code",
+            "execution_count": null,
+            "id": leaf.id(),
+            "metadata": { "tangle": meta },
+            "outputs": outputs,
+            "source": source_lines(&code),
+        }));
+    }
+    if !end.is_empty() {
+        json.push(serde_json::json!({
+            "cell_type": "markdown",
+            "id": "end-md",
+            "metadata": {},
+            "source": source_lines(end),
+        }));
+    }
+    let notebook = serde_json::json!({
+        "cells": json,
+        "metadata": {
+            "kernelspec": {
+                "display_name": "Python 3",
+                "language": "python",
+                "name": "python3",
+            },
+            "language_info": { "name": "python" },
+        },
+        "nbformat": 4,
+        "nbformat_minor": 5,
+    });
+    Ok(serde_json::to_string_pretty(&notebook).unwrap() + "\n")
+}
+
+/// Parses the title and `key="value"` options of a `# %%` marker.
+fn parse_marker(marker: &str) -> (Option<String>, bool, IndexMap<String, String>) {
+    let mut title = None;
+    let mut markdown = false;
+    let mut options: IndexMap<String, String> = Default::default();
+    for word in marker.split_whitespace() {
+        if word == "[markdown]" || word == "[md]" {
+            markdown = true;
+        } else if let Some((key, value)) = word.split_once('=') {
+            options.insert(key.to_string(), value.trim_matches('"').to_string());
+        } else if title.is_none() {
+            title = Some(word.to_string());
+        }
+    }
+    (title, markdown, options)
+}
+
+/// Imports a jupytext percent script, where every cell starts with a
+/// `# %% name` line and markdown cells with `# %% [markdown]`. Cells are
+/// named after their marker when it is a valid identifier.
+pub fn import_percent(code: &str) -> ImportedNotebook {
+    let mut raw = vec![];
+    let mut marker: Option<&str> = None;
+    let mut lines: Vec<&str> = vec![];
+    let mut flush = |marker: Option<&str>, lines: &mut Vec<&str>| {
+        let text = std::mem::take(lines).join("\n");
+        let text = text.trim_matches('\n').to_string();
+        let (title, markdown, mut options) = parse_marker(marker.unwrap_or(""));
+        if markdown {
+            let text = text
+                .lines()
+                .map(|l| l.strip_prefix("# ").or(l.strip_prefix("#")).unwrap_or(l))
+                .collect::<Vec<_>>()
+                .join("\n");
+            raw.push(RawCell::Markdown(text));
+        } else if marker.is_some() || !text.trim().is_empty() {
+            raw.push(RawCell::Code {
+                name: title,
+                id: options.remove("id"),
+                code: text,
+            });
+        }
+    };
+    for line in code.lines() {
+        let line = line.trim_end_matches('\r');
+        if let Some(next) = line.strip_prefix("# %%") {
+            flush(marker, &mut lines);
+            marker = Some(next);
+        } else {
+            lines.push(line);
+        }
+    }
+    flush(marker, &mut lines);
+    import_cells(raw)
+}
+
+/// A `# %% [markdown]` cell.
+fn percent_markdown(markdown: &str) -> String {
+    let text = markdown
+        .lines()
+        .map(|l| {
+            if l.is_empty() {
+                "#".to_string()
+            } else {
+                format!("# {}", l)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("# %% [markdown]\n{}", text)
+}
+
+/// Writes `cells` as a jupytext percent script in the order of the flow, each
+/// cell marker keeps the node name and id so the script imports back into
+/// the same nodes. `end` is the markdown after the last cell.
+pub fn export_percent(cells: &[(&Tangle, &str)], end: &str) -> Result<String, Error> {
+    let cells: Vec<_> = cells
+        .iter()
+        .map(|(leaf, markdown)| (*leaf, *markdown, None::<()>))
+        .collect();
+    let mut script = vec![];
+    for (leaf, markdown, _) in in_flow_order(&cells)? {
+        if !markdown.is_empty() {
+            script.push(percent_markdown(markdown));
+        }
+        let code = leaf
+            .code()
+            .lines()
+            .map(|l| l.trim_end_matches('\r'))
+            .collect::<Vec<_>>();
+        script.push(format!(
+            "# %% {} id=\"{}\"\n{}",
+            leaf.name(),
+            leaf.id(),
+            code.join("\n")
+        ));
+    }
+    if !end.is_empty() {
+        script.push(percent_markdown(end));
+    }
+    Ok(script.join("\n\n") + "\n")
+}
+
+fn import_cells(raw: Vec<RawCell>) -> ImportedNotebook {
+    let mut code_cells: Vec<(Option<String>, Option<String>, String, String)> = vec![];
+    let mut markdown: Vec<String> = vec![];
+    for cell in raw {
+        match cell {
+            RawCell::Code { name, id, code } => code_cells.push((
+                name,
+                id,
+                comment_magics(&code),
+                std::mem::take(&mut markdown).join("\n\n"),
+            )),
+            RawCell::Markdown(text) => markdown.push(text),
+        }
+    }
+
+    // cells that don't parse keep their names, the leaf reports the error
+    let cell_uses: Vec<Vec<Use>> = code_cells
+        .iter()
+        .map(|(_, _, code, _)| scope::analyze(code).map_or_else(|_| vec![], |a| a.uses))
+        .collect();
+    let mut taken: IndexSet<String> = cell_uses.iter().flatten().map(|u| u.name.clone()).collect();
+
+    // the name each variable currently has in the flow
+    let mut current: IndexMap<String, String> = Default::default();
+    let mut unresolved = vec![];
+    let mut cells = vec![];
+    let mut names: IndexSet<String> = Default::default();
+    for (n, ((name, id, code, markdown), uses)) in code_cells.into_iter().zip(cell_uses).enumerate()
+    {
+        let name = name
+            .filter(|name| is_identifier(name) && !names.contains(name))
+            .unwrap_or_else(|| {
+                (n + 1..)
+                    .map(|k| format!("cell_{}", k))
+                    .find(|name| !names.contains(name))
+                    .unwrap()
+            });
+        names.insert(name.clone());
+        let mut renamed: IndexMap<String, String> = Default::default();
+        let mut edits: Vec<(Range<usize>, String)> = vec![];
+        let mut mutated: IndexSet<String> = Default::default();
+        for u in uses {
+            // every use after a write in this cell refers to that write
+            if let Some(var) = renamed.get(&u.name) {
+                if u.access == Access::Import && var != &u.name {
+                    edits.push((u.bytes, format!("{} as {}", u.name, var)));
+                } else {
+                    edits.push((u.bytes, var.clone()));
+                }
+                continue;
+            }
+            match (u.access, current.get(&u.name).cloned()) {
+                (Access::Write, None) | (Access::Import, None) => {
+                    renamed.insert(u.name.clone(), u.name.clone());
+                }
+                (_, None) => {}
+                (Access::Read, Some(var)) => edits.push((u.bytes, var)),
+                (Access::Mutate, Some(var)) => {
+                    edits.push((u.bytes, var));
+                    mutated.insert(u.name.clone());
+                }
+                (Access::Unsupported(reason), Some(var)) => {
+                    if !unresolved
+                        .iter()
+                        .any(|r: &Unresolved| r.var == u.name && r.cell == name)
+                    {
+                        unresolved.push(Unresolved {
+                            var: u.name.clone(),
+                            cell: name.clone(),
+                            reason,
+                        });
+                    }
+                    edits.push((u.bytes, var));
+                }
+                (access, Some(_)) => {
+                    let var = (1..)
+                        .map(|k| format!("{}_{}", u.name, k))
+                        .find(|v| !taken.contains(v))
+                        .unwrap();
+                    taken.insert(var.clone());
+                    renamed.insert(u.name.clone(), var.clone());
+                    if access == Access::Import {
+                        edits.push((u.bytes, format!("{} as {}", u.name, var)));
+                    } else {
+                        edits.push((u.bytes, var));
+                    }
+                }
+            }
+        }
+
+        let mut code = code;
+        edits.sort_by_key(|(bytes, _)| bytes.start);
+        for (bytes, var) in edits.into_iter().rev() {
+            if code[bytes.clone()] != var {
+                code.replace_range(bytes, &var);
+            }
+        }
+        // a changed variable is provided again under a new name, so the cells
+        // reading it later run after this one
+        for name in mutated {
+            if renamed.contains_key(&name) {
+                continue;
+            }
+            let var = (1..)
+                .map(|k| format!("{}_{}", name, k))
+                .find(|v| !taken.contains(v))
+                .unwrap();
+            taken.insert(var.clone());
+            code = format!("{}\n{} = {}\n", code.trim_end(), var, current[&name]);
+            renamed.insert(name, var);
+        }
+        current.extend(renamed);
+        let mut leaf = match Tangle::from_cell(name, &code) {
+            Ok((leaf, _)) => leaf,
+            Err((_, (partial_leaf, _))) => partial_leaf,
+        };
+        leaf.set_id(id.unwrap_or_else(fresh_id));
+        cells.push(ImportedCell { leaf, markdown });
+    }
+
+    ImportedNotebook {
+        cells,
+        markdown: markdown.join("\n\n"),
+        unresolved,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(cells: &[&str]) -> ImportedNotebook {
+        let cells: Vec<_> = cells
+            .iter()
+            .map(|code| serde_json::json!({ "cell_type": "code", "source": code }))
+            .collect();
+        import_notebook(&serde_json::json!({ "cells": cells }).to_string()).unwrap()
+    }
+
+    fn code(nb: &ImportedNotebook) -> Vec<String> {
+        nb.cells.iter().map(|c| c.leaf.code().clone()).collect()
+    }
+
+    #[test]
+    fn reassignments_are_renamed() {
+        let nb = import(&["x = 1", "x = x + 1", "y = x"]);
+        assert_eq!(code(&nb), ["x = 1", "x_1 = x + 1", "y = x_1"]);
+        assert!(nb.unresolved.is_empty());
+        nb.flow("nb").unwrap();
+    }
+
+    #[test]
+    fn loop_targets_are_renamed() {
+        let nb = import(&["i = 0", "for i in range(3):\n    print(i)", "j = i"]);
+        assert_eq!(
+            code(&nb),
+            ["i = 0", "for i_1 in range(3):\n    print(i_1)", "j = i_1"]
+        );
+        assert!(nb.unresolved.is_empty());
+    }
+
+    #[test]
+    fn imports_are_aliased() {
+        let nb = import(&["import np", "import np\nnp.x", "a = np"]);
+        assert_eq!(
+            code(&nb),
+            ["import np", "import np as np_1\nnp_1.x", "a = np_1"]
+        );
+    }
+
+    #[test]
+    fn locals_are_not_renamed() {
+        let nb = import(&[
+            "x = 1",
+            "x = 2\ndef f(x):\n    return x\nys = [x for x in range(x)]",
+        ]);
+        assert_eq!(
+            code(&nb)[1],
+            "x_1 = 2\ndef f(x):\n    return x\nys = [x for x in range(x_1)]"
+        );
+    }
+
+    #[test]
+    fn mutations_are_versioned() {
+        let nb = import(&["xs = []", "xs.append(1)", "n = len(xs)"]);
+        assert_eq!(
+            code(&nb),
+            ["xs = []", "xs.append(1)\nxs_1 = xs\n", "n = len(xs_1)"]
+        );
+        nb.flow("nb").unwrap();
+    }
+
+    #[test]
+    fn unresolved_reassignments() {
+        let nb = import(&["x = 1\ny = 1", "if x:\n    x = 2", "y += 1"]);
+        let unresolved: Vec<_> = nb
+            .unresolved
+            .iter()
+            .map(|u| (u.var.as_str(), u.cell.as_str(), u.reason))
+            .collect();
+        assert_eq!(
+            unresolved,
+            [
+                ("x", "cell_2", "conditionally"),
+                ("y", "cell_3", "in place")
+            ]
+        );
+    }
+
+    #[test]
+    fn percent_scripts_round_trip() {
+        let script = "# %% [markdown]\n# intro\n\n\
+                      # %% load id=\"1\"\nx = 1\n\n\
+                      # %% [markdown]\n# the end\n#\n# bye\n";
+        let nb = import_percent(script);
+        assert_eq!(nb.cells.len(), 1);
+        assert_eq!(nb.cells[0].leaf.name(), "load");
+        assert_eq!(nb.cells[0].markdown, "intro");
+        assert_eq!(nb.markdown, "the end\n\nbye");
+        let cells: Vec<_> = nb
+            .cells
+            .iter()
+            .map(|c| (&c.leaf, c.markdown.as_str()))
+            .collect();
+        assert_eq!(export_percent(&cells, &nb.markdown).unwrap(), script);
+    }
+
+    #[test]
+    fn exported_notebooks_round_trip() {
+        let code =
+            "@cache\ndef one(scale: float = 1.0):\n    x = scale  # scaled\n    return [x]\n\n\
+                    def main():\n    [x] = one()\n    return [x]\n";
+        let flows = Tangle::from_code(code.to_string()).unwrap().flows;
+        let leaf = flows["main"].leaves()[0].1.clone();
+        let json = export_notebook(&[(&leaf, "", None)], "").unwrap();
+        let json = json.replace("scale = 1.0", "scale = 2.5");
+        let nb = import_notebook(&json).unwrap();
+        let imported = &nb.cells[0].leaf;
+        assert_eq!(imported.code(), leaf.code());
+        assert_eq!(imported.annotations()["scale"], "float");
+        assert_eq!(imported.defaults()["scale"], "2.5");
+        let trivia = Trivia {
+            span: None,
+            ..leaf.trivia().clone()
+        };
+        assert_eq!(imported.trivia(), &trivia);
+        assert_eq!(trivia.decorators, vec!["@cache"]);
+    }
+
+    #[test]
+    fn notebooks_keep_outputs_of_flattened_flows() {
+        let code = "def load():\n    xs = [1]\n    return [xs]\n\n\
+                    def total(xs):\n    s = sum(xs)\n    return [s]\n\n\
+                    def prep():\n    [xs] = load()\n    return [xs]\n\n\
+                    def main():\n    [xs] = prep()\n    [s] = total(xs)\n    return [s]\n";
+        let flows = Tangle::from_code(code.to_string()).unwrap().flows;
+        let nodes = match &flows["main"] {
+            Tangle::Dataflow { nodes, .. } => nodes,
+            _ => unreachable!(),
+        };
+        let result = Output::Result("[[1]]".to_string());
+        let error = Output::Error {
+            ename: "ValueError".to_string(),
+            evalue: "no".to_string(),
+            traceback: vec!["ValueError: no".to_string()],
+        };
+        let json = export_notebook(
+            &[
+                (&nodes["total"], "", Some(&error)),
+                (&nodes["prep"], "intro", Some(&result)),
+            ],
+            "",
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let cells = json["cells"].as_array().unwrap();
+        assert_eq!(cells.len(), 3);
+        assert_eq!(cells[0]["source"], serde_json::json!(["intro"]));
+        assert_eq!(cells[1]["metadata"]["tangle"]["name"], "load");
+        assert_eq!(cells[1]["outputs"][0]["output_type"], "execute_result");
+        assert_eq!(
+            cells[1]["outputs"][0]["data"]["text/plain"],
+            serde_json::json!(["[[1]]"])
+        );
+        assert_eq!(cells[2]["metadata"]["tangle"]["name"], "total");
+        assert_eq!(cells[2]["outputs"][0]["output_type"], "error");
+        assert_eq!(cells[2]["outputs"][0]["ename"], "ValueError");
+    }
+}
@@ -0,0 +1,277 @@
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use tree_sitter::{Node, Query, QueryMatch};
+
+use crate::tangle::{Error, ErrorKind, Span};
+
+const FILES: [&str; 3] = ["query.scm", "node_query.scm", "cell_query.scm"];
+
+const BUILTIN: [&str; 3] = [
+    include_str!("query.scm"),
+    include_str!("node_query.scm"),
+    include_str!("cell_query.scm"),
+];
+
+/// Captures each query has to define, for the leaf and node queries in each
+/// of their patterns.
+const REQUIRED: [&[&str]; 3] = [
+    &["leaf", "leaf.name", "leaf.param", "leaf.body"],
+    &["flow.node.name", "flow.node.param", "flow.node.provides"],
+    &["identifier"],
+];
+
+/// Captures each query may define besides the required ones. Captures
+/// starting with `_` are free for predicates like `(#eq? @_name "node")`.
+const OPTIONAL: [&[&str]; 3] = [
+    &[],
+    &[],
+    &["function", "class", "variable", "import", "error"],
+];
+
+/// The tree-sitter queries code is recognized with: `query.scm` finds the
+/// leaves, `node_query.scm` the node calls of a flow and `cell_query.scm` the
+/// names a cell binds.
+pub struct QueryPack {
+    pub leaf: Query,
+    pub node: Query,
+    pub cell: Query,
+    pub leaf_def: u32,
+    pub leaf_name: u32,
+    pub leaf_param: u32,
+    pub leaf_body: u32,
+    pub node_name: u32,
+    pub node_param: u32,
+    pub node_provides: u32,
+}
+
+lazy_static! {
+    static ref ACTIVE: RwLock<Arc<QueryPack>> = RwLock::new(Arc::new(QueryPack::builtin()));
+}
+
+/// The rest of the line of `source` from `at`, where an error is shown.
+fn line_span(source: &str, at: usize) -> Span {
+    let end = source[at..].find('\n').map_or(source.len(), |n| at + n);
+    Span::in_text(source, at..end)
+}
+
+fn compile(
+    file: &str,
+    source: &str,
+    required: &[&str],
+    optional: &[&str],
+    each: bool,
+) -> Result<Query, Error> {
+    let query = Query::new(tree_sitter_python::language(), source).map_err(|err| {
+        ErrorKind::InvalidQuery {
+            file: file.to_string(),
+            message: format!("{:?} at line {}: {}", err.kind, err.row + 1, err.message),
+            span: Some(line_span(source, err.offset.min(source.len()))),
+        }
+    })?;
+    // leaf and node matches are read one pattern at a time, so there each
+    // pattern needs the captures
+    let mut starts: Vec<usize> = (0..query.pattern_count())
+        .map(|pattern| query.start_byte_for_pattern(pattern))
+        .take(if each { usize::MAX } else { 1 })
+        .collect();
+    if starts.is_empty() {
+        starts.push(0);
+    }
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(source.len());
+        let captures = Query::new(tree_sitter_python::language(), &source[start..end])
+            .map(|alone| alone.capture_names().to_vec())
+            .unwrap_or_default();
+        if let Some(capture) = required
+            .iter()
+            .find(|capture| !captures.iter().any(|c| c == *capture))
+        {
+            return Err(ErrorKind::MissingCapture {
+                file: file.to_string(),
+                capture: capture.to_string(),
+                span: line_span(source, start),
+            }
+            .into());
+        }
+    }
+    for capture in query.capture_names() {
+        if !capture.starts_with('_')
+            && !required.contains(&capture.as_str())
+            && !optional.contains(&capture.as_str())
+        {
+            let at = source.find(&format!("@{}", capture)).unwrap_or(0);
+            return Err(ErrorKind::UnknownCapture {
+                file: file.to_string(),
+                capture: capture.clone(),
+                span: Span::in_text(source, at..at + capture.len() + 1),
+            }
+            .into());
+        }
+    }
+    Ok(query)
+}
+
+fn captured<'t>(
+    query: &Query,
+    file: &str,
+    qm: &QueryMatch<'_, 't>,
+    capture: u32,
+) -> Result<Node<'t>, Error> {
+    qm.nodes_for_capture_index(capture).next().ok_or_else(|| {
+        ErrorKind::InvalidQuery {
+            file: file.to_string(),
+            message: format!(
+                "pattern {} matched without `@{}`",
+                qm.pattern_index + 1,
+                query.capture_names()[capture as usize]
+            ),
+            span: None,
+        }
+        .into()
+    })
+}
+
+impl QueryPack {
+    /// The queries shipped with tangle.
+    pub fn builtin() -> Self {
+        QueryPack::from_sources(BUILTIN).unwrap()
+    }
+
+    /// Compiles and validates the sources of `query.scm`, `node_query.scm`
+    /// and `cell_query.scm`, in that order.
+    pub fn from_sources(sources: [&str; 3]) -> Result<Self, Error> {
+        let leaf = compile(FILES[0], sources[0], REQUIRED[0], OPTIONAL[0], true)?;
+        let node = compile(FILES[1], sources[1], REQUIRED[1], OPTIONAL[1], true)?;
+        let cell = compile(FILES[2], sources[2], REQUIRED[2], OPTIONAL[2], false)?;
+        let index = |query: &Query, name| query.capture_index_for_name(name).unwrap();
+        Ok(QueryPack {
+            leaf_def: index(&leaf, "leaf"),
+            leaf_name: index(&leaf, "leaf.name"),
+            leaf_param: index(&leaf, "leaf.param"),
+            leaf_body: index(&leaf, "leaf.body"),
+            node_name: index(&node, "flow.node.name"),
+            node_param: index(&node, "flow.node.param"),
+            node_provides: index(&node, "flow.node.provides"),
+            leaf,
+            node,
+            cell,
+        })
+    }
+
+    /// Loads a pack from a directory. Queries the directory has no file for
+    /// stay the built-in ones.
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        let mut sources = vec![];
+        for (file, builtin) in FILES.iter().zip(BUILTIN) {
+            let path = dir.join(file);
+            sources.push(if path.exists() {
+                std::fs::read_to_string(&path).map_err(|err| ErrorKind::InvalidQuery {
+                    file: path.display().to_string(),
+                    message: err.to_string(),
+                    span: None,
+                })?
+            } else {
+                builtin.to_string()
+            });
+        }
+        QueryPack::from_sources([&sources[0], &sources[1], &sources[2]])
+    }
+
+    /// The node `capture` of a match of the leaf query. Quantified captures
+    /// like `@leaf.name?` can be missing from a match.
+    pub fn leaf_capture<'t>(
+        &self,
+        qm: &QueryMatch<'_, 't>,
+        capture: u32,
+    ) -> Result<Node<'t>, Error> {
+        captured(&self.leaf, FILES[0], qm, capture)
+    }
+
+    /// The node `capture` of a match of the node query.
+    pub fn node_capture<'t>(
+        &self,
+        qm: &QueryMatch<'_, 't>,
+        capture: u32,
+    ) -> Result<Node<'t>, Error> {
+        captured(&self.node, FILES[1], qm, capture)
+    }
+
+    /// The pack `from_code` and `from_cell` currently use.
+    pub fn active() -> Arc<QueryPack> {
+        ACTIVE.read().unwrap().clone()
+    }
+
+    /// Makes this pack the one code is parsed with from now on.
+    pub fn activate(self) {
+        *ACTIVE.write().unwrap() = Arc::new(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(sources: [&str; 3]) -> Error {
+        match QueryPack::from_sources(sources) {
+            Ok(_) => panic!("the pack is valid"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn builtin() {
+        QueryPack::builtin();
+    }
+
+    #[test]
+    fn invalid_query() {
+        let err = error(["(function_definition", BUILTIN[1], BUILTIN[2]]);
+        assert!(matches!(err.kind(), ErrorKind::InvalidQuery { file, .. } if file == "query.scm"));
+        assert!(err.span().is_some());
+    }
+
+    #[test]
+    fn missing_capture() {
+        let leaf = "\n(function_definition name: (identifier) @leaf.name) @leaf";
+        let err = error([leaf, BUILTIN[1], BUILTIN[2]]);
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::MissingCapture { capture, .. } if capture == "leaf.param"
+        ));
+        assert_eq!(err.span().map(|span| span.start.row), Some(1));
+    }
+
+    #[test]
+    fn missing_capture_in_a_pattern() {
+        let leaf = format!(
+            "{}\n(class_definition name: (identifier) @leaf.name) @leaf",
+            BUILTIN[0]
+        );
+        let err = error([&leaf, BUILTIN[1], BUILTIN[2]]);
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::MissingCapture { capture, .. } if capture == "leaf.param"
+        ));
+        assert_eq!(err.span().map(|span| span.start.row), Some(4));
+    }
+
+    #[test]
+    fn unknown_capture() {
+        let cell = "(identifier) @identifier\n(call) @call";
+        let err = error([BUILTIN[0], BUILTIN[1], cell]);
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::UnknownCapture { capture, .. } if capture == "call"
+        ));
+        let span = err.span().unwrap();
+        assert_eq!(&cell[span.bytes.clone()], "@call");
+        assert_eq!((span.start.row, span.start.column), (1, 7));
+    }
+
+    #[test]
+    fn predicate_captures() {
+        let cell = "(call function: (identifier) @_name (#eq? @_name \"x\")) @identifier";
+        assert!(QueryPack::from_sources([BUILTIN[0], BUILTIN[1], cell]).is_ok());
+    }
+}
@@ -0,0 +1,1002 @@
+use std::ops::Range;
+
+use tree_sitter::Node;
+
+use crate::tangle::{Error, ErrorKind, IndexMap, IndexSet, Span};
+
+/// Names of the `builtins` module of Python 3.8 to 3.13, never required from
+/// another node.
+const BUILTINS: &[&str] = &[
+    "ArithmeticError",
+    "AssertionError",
+    "AttributeError",
+    "BaseException",
+    "BaseExceptionGroup",
+    "BlockingIOError",
+    "BrokenPipeError",
+    "BufferError",
+    "BytesWarning",
+    "ChildProcessError",
+    "ConnectionAbortedError",
+    "ConnectionError",
+    "ConnectionRefusedError",
+    "ConnectionResetError",
+    "DeprecationWarning",
+    "EOFError",
+    "Ellipsis",
+    "EncodingWarning",
+    "EnvironmentError",
+    "Exception",
+    "ExceptionGroup",
+    "False",
+    "FileExistsError",
+    "FileNotFoundError",
+    "FloatingPointError",
+    "FutureWarning",
+    "GeneratorExit",
+    "IOError",
+    "ImportError",
+    "ImportWarning",
+    "IndentationError",
+    "IndexError",
+    "InterruptedError",
+    "IsADirectoryError",
+    "KeyError",
+    "KeyboardInterrupt",
+    "LookupError",
+    "MemoryError",
+    "ModuleNotFoundError",
+    "NameError",
+    "None",
+    "NotADirectoryError",
+    "NotImplemented",
+    "NotImplementedError",
+    "OSError",
+    "OverflowError",
+    "PendingDeprecationWarning",
+    "PermissionError",
+    "ProcessLookupError",
+    "PythonFinalizationError",
+    "RecursionError",
+    "ReferenceError",
+    "ResourceWarning",
+    "RuntimeError",
+    "RuntimeWarning",
+    "StopAsyncIteration",
+    "StopIteration",
+    "SyntaxError",
+    "SyntaxWarning",
+    "SystemError",
+    "SystemExit",
+    "TabError",
+    "TimeoutError",
+    "True",
+    "TypeError",
+    "UnboundLocalError",
+    "UnicodeDecodeError",
+    "UnicodeEncodeError",
+    "UnicodeError",
+    "UnicodeTranslateError",
+    "UnicodeWarning",
+    "UserWarning",
+    "ValueError",
+    "Warning",
+    "ZeroDivisionError",
+    "__build_class__",
+    "__debug__",
+    "__doc__",
+    "__import__",
+    "__loader__",
+    "__name__",
+    "__package__",
+    "__spec__",
+    "abs",
+    "aiter",
+    "all",
+    "anext",
+    "any",
+    "ascii",
+    "bin",
+    "bool",
+    "breakpoint",
+    "bytearray",
+    "bytes",
+    "callable",
+    "chr",
+    "classmethod",
+    "compile",
+    "complex",
+    "copyright",
+    "credits",
+    "delattr",
+    "dict",
+    "dir",
+    "divmod",
+    "enumerate",
+    "eval",
+    "exec",
+    "exit",
+    "filter",
+    "float",
+    "format",
+    "frozenset",
+    "getattr",
+    "globals",
+    "hasattr",
+    "hash",
+    "help",
+    "hex",
+    "id",
+    "input",
+    "int",
+    "isinstance",
+    "issubclass",
+    "iter",
+    "len",
+    "license",
+    "list",
+    "locals",
+    "map",
+    "max",
+    "memoryview",
+    "min",
+    "next",
+    "object",
+    "oct",
+    "open",
+    "ord",
+    "pow",
+    "print",
+    "property",
+    "quit",
+    "range",
+    "repr",
+    "reversed",
+    "round",
+    "set",
+    "setattr",
+    "slice",
+    "sorted",
+    "staticmethod",
+    "str",
+    "sum",
+    "super",
+    "tuple",
+    "type",
+    "vars",
+    "zip",
+];
+
+/// Methods of `list`, `dict` and `set` that change them in place. Other types
+/// may have pure methods of the same name, like `DataFrame.add`, so a call is
+/// only a guess.
+const MUTATORS: &[&str] = &[
+    "append",
+    "extend",
+    "insert",
+    "remove",
+    "pop",
+    "popitem",
+    "clear",
+    "sort",
+    "reverse",
+    "update",
+    "setdefault",
+    "add",
+    "discard",
+    "difference_update",
+    "intersection_update",
+    "symmetric_difference_update",
+];
+
+const COMPREHENSIONS: &[&str] = &[
+    "list_comprehension",
+    "set_comprehension",
+    "dictionary_comprehension",
+    "generator_expression",
+];
+
+const PATTERNS: &[&str] = &[
+    "pattern_list",
+    "tuple_pattern",
+    "list_pattern",
+    "list_splat_pattern",
+    "parenthesized_expression",
+    "expression_list",
+    "tuple",
+    "list",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    /// functions, lambdas and comprehensions
+    Function,
+    /// class bodies, which are skipped when resolving names of inner scopes
+    Class,
+}
+
+#[derive(Debug)]
+struct Scope {
+    kind: Kind,
+    bound: IndexSet<String>,
+    globals: IndexSet<String>,
+    nonlocals: IndexSet<String>,
+}
+
+impl Scope {
+    fn new(kind: Kind, bound: IndexSet<String>) -> Self {
+        Scope {
+            kind,
+            bound,
+            globals: Default::default(),
+            nonlocals: Default::default(),
+        }
+    }
+}
+
+/// Resolves every name read by a cell against the scopes it is nested in,
+/// the same way CPython decides between fast, closure and global loads.
+struct Resolver<'c> {
+    code: &'c str,
+    scopes: Vec<Scope>,
+    analysis: Analysis,
+    /// where the variables changed in place start
+    mutated: IndexSet<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct Analysis {
+    /// globals the cell reads, in order of first use
+    pub requires: IndexSet<String>,
+    /// required names the cell changes in place, with their first change
+    pub mutates: IndexMap<String, Change>,
+    /// every use of a name shared with other cells, in evaluation order
+    pub uses: Vec<Use>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// a bare `import x`, renamed by aliasing it
+    Import,
+    /// a change in place like `x.append(..)`
+    Mutate,
+    /// a write that doesn't simply replace the value, like `x += 1` or a
+    /// write inside a branch
+    Unsupported(&'static str),
+}
+
+/// Where a cell changes a required name in place.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub span: Span,
+    /// only a method name like `append` tells, see `MUTATORS`
+    pub guessed: bool,
+}
+
+/// A name of the cell's namespace, bound by the cell or read from another one.
+#[derive(Debug, Clone)]
+pub struct Use {
+    pub name: String,
+    pub bytes: Range<usize>,
+    pub access: Access,
+}
+
+/// The globals a cell reads and changes. The cell is treated as the body of a
+/// function, so every name it binds is local to it.
+pub fn analyze(code: &str) -> Result<Analysis, Error> {
+    let mut parser = tree_sitter::Parser::new();
+    parser.set_language(tree_sitter_python::language()).unwrap();
+    let tree = parser.parse(code, None).unwrap();
+    let root = tree.root_node();
+    if root.has_error() {
+        return Err(ErrorKind::InvalidSyntax {
+            span: first_error(root).into(),
+        }
+        .into());
+    }
+    // nodes run as functions, where Python only allows explicit imports
+    if let Some(wildcard) = find(root, "wildcard_import") {
+        return Err(ErrorKind::WildcardImport {
+            span: wildcard.parent().unwrap().into(),
+        }
+        .into());
+    }
+    let mut resolver = Resolver {
+        code,
+        scopes: vec![],
+        analysis: Default::default(),
+        mutated: Default::default(),
+    };
+    resolver.enter(Kind::Function, Default::default(), root);
+    resolver.walk(root);
+    Ok(resolver.analysis)
+}
+
+/// Whether a cell has to run as a coroutine, because it uses `await`,
+/// `async for` or `async with` outside of the functions it defines.
+pub fn awaits(code: &str) -> bool {
+    let mut parser = tree_sitter::Parser::new();
+    parser.set_language(tree_sitter_python::language()).unwrap();
+    let tree = parser.parse(code, None).unwrap();
+    awaits_in(tree.root_node())
+}
+
+fn awaits_in(node: Node) -> bool {
+    match node.kind() {
+        "await" => true,
+        "function_definition" | "lambda" => false,
+        kind => {
+            let asynchronous = matches!(kind, "for_statement" | "with_statement" | "for_in_clause")
+                && node.child(0).map(|c| c.kind()) == Some("async");
+            let mut cursor = node.walk();
+            let found = asynchronous || node.children(&mut cursor).any(awaits_in);
+            found
+        }
+    }
+}
+
+/// The variable changed in place by an assignment target like `df["c"]` or
+/// `obj.attr`, or by a call like `xs.append(x)` or `df.drop(.., inplace=True)`,
+/// and whether that is only guessed from the method name.
+pub fn mutated<'t>(node: Node<'t>, code: &str) -> Option<(Node<'t>, bool)> {
+    match node.kind() {
+        "attribute" | "subscript" => Some((root(node)?, false)),
+        "call" => {
+            let method = node.child_by_field_name("function")?;
+            let name = &code[method.child_by_field_name("attribute")?.byte_range()];
+            let inplace = node.child_by_field_name("arguments").is_some_and(|args| {
+                let mut cursor = args.walk();
+                let found = args.named_children(&mut cursor).any(|arg| {
+                    let name = arg.child_by_field_name("name");
+                    let value = arg.child_by_field_name("value");
+                    name.map(|n| &code[n.byte_range()]) == Some("inplace")
+                        && value.map(|v| v.kind()) == Some("true")
+                });
+                found
+            });
+            if MUTATORS.contains(&name) || inplace {
+                Some((root(method.child_by_field_name("object")?)?, !inplace))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether a statement only runs on some paths, in a branch, loop or `try`.
+fn conditional(node: Node) -> bool {
+    let mut node = node;
+    while let Some(parent) = node.parent() {
+        let guarded = parent.parent().map(|p| p.kind()) != Some("with_statement");
+        if parent.kind() == "block" && guarded {
+            return true;
+        }
+        node = parent;
+    }
+    false
+}
+
+/// The variable at the bottom of `a.b[c].d`.
+fn root(node: Node) -> Option<Node> {
+    match node.kind() {
+        "identifier" => Some(node),
+        "attribute" => root(node.child_by_field_name("object")?),
+        "subscript" => root(node.child_by_field_name("value")?),
+        _ => None,
+    }
+}
+
+fn find<'t>(node: Node<'t>, kind: &str) -> Option<Node<'t>> {
+    if node.kind() == kind {
+        return Some(node);
+    }
+    let mut cursor = node.walk();
+    let found = node.named_children(&mut cursor).find_map(|c| find(c, kind));
+    found
+}
+
+fn first_error(node: Node) -> Node {
+    let mut cursor = node.walk();
+    let child = node
+        .children(&mut cursor)
+        .find(|c| c.has_error() || c.is_error() || c.is_missing());
+    match child {
+        Some(child) if !child.is_error() && !child.is_missing() => first_error(child),
+        Some(child) => child,
+        None => node,
+    }
+}
+
+impl<'c> Resolver<'c> {
+    fn text(&self, node: Node) -> String {
+        self.code[node.byte_range()].to_string()
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn enter(&mut self, kind: Kind, bound: IndexSet<String>, body: Node) {
+        self.scopes.push(Scope::new(kind, bound));
+        self.bindings(body);
+    }
+
+    /// Whether `name` read in the current scope resolves to a global.
+    fn is_global(&self, name: &str) -> bool {
+        let (innermost, outer) = self.scopes.split_last().unwrap();
+        if innermost.globals.contains(name) {
+            true
+        } else if innermost.bound.contains(name) && !innermost.nonlocals.contains(name) {
+            false
+        } else {
+            // class bodies are invisible to the scopes nested in them
+            outer
+                .iter()
+                .rev()
+                .filter(|s| s.kind == Kind::Function && !s.nonlocals.contains(name))
+                .find(|s| s.globals.contains(name) || s.bound.contains(name))
+                .is_none_or(|s| s.globals.contains(name))
+        }
+    }
+
+    /// Whether `name` in the current scope is a name of the cell, or a global.
+    fn is_shared(&self, name: &str) -> bool {
+        let (innermost, outer) = self.scopes.split_last().unwrap();
+        if outer.is_empty() || innermost.globals.contains(name) {
+            true
+        } else if innermost.bound.contains(name) && !innermost.nonlocals.contains(name) {
+            false
+        } else {
+            outer
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, s)| s.kind == Kind::Function && !s.nonlocals.contains(name))
+                .find(|(_, s)| s.globals.contains(name) || s.bound.contains(name))
+                .is_none_or(|(i, s)| i == 0 || s.globals.contains(name))
+        }
+    }
+
+    fn record(&mut self, node: Node, access: Access) {
+        let name = self.text(node);
+        if self.is_shared(&name) {
+            self.analysis.uses.push(Use {
+                name,
+                bytes: node.byte_range(),
+                access,
+            });
+        }
+    }
+
+    /// A write of `node`, which only replaces the value of the cell's name if
+    /// it always runs.
+    fn write(&mut self, node: Node) {
+        let name = self.text(node);
+        let access = if self.scopes.len() == 1 && !conditional(node) {
+            Access::Write
+        } else if self.scope().globals.contains(&name) {
+            Access::Unsupported("through `global`")
+        } else {
+            Access::Unsupported("conditionally")
+        };
+        self.record(node, access);
+    }
+
+    fn read(&mut self, node: Node) {
+        let name = self.text(node);
+        if self.mutated.contains(&node.start_byte()) {
+            self.record(node, Access::Mutate);
+        } else {
+            self.record(node, Access::Read);
+        }
+        if self.is_global(&name) && !BUILTINS.contains(&name.as_str()) {
+            self.analysis.requires.insert(name);
+        }
+    }
+
+    fn mutate(&mut self, node: Node) {
+        if let Some((var, guessed)) = mutated(node, self.code) {
+            self.mutated.insert(var.start_byte());
+            let name = self.text(var);
+            if self.is_global(&name) && !BUILTINS.contains(&name.as_str()) {
+                self.analysis.mutates.entry(name).or_insert_with(|| Change {
+                    span: node.into(),
+                    guessed,
+                });
+            }
+        }
+    }
+
+    /// Records the names a scope binds, without entering nested scopes.
+    fn bindings(&mut self, node: Node) {
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor) {
+            match child.kind() {
+                "function_definition" | "class_definition" => {
+                    if let Some(name) = child.child_by_field_name("name") {
+                        let name = self.text(name);
+                        self.scope().bound.insert(name);
+                    }
+                    // decorators, defaults and annotations still run in this scope
+                    let mut cursor = child.walk();
+                    for part in child.named_children(&mut cursor) {
+                        if part.kind() != "block" {
+                            self.bindings(part);
+                        }
+                    }
+                }
+                "lambda" => {}
+                kind if COMPREHENSIONS.contains(&kind) => {
+                    if let Some(first) = child.named_child(1) {
+                        if let Some(iter) = first.child_by_field_name("right") {
+                            self.bindings(iter);
+                        }
+                    }
+                    self.walrus(child);
+                }
+                "global_statement" | "nonlocal_statement" => {
+                    let mut cursor = child.walk();
+                    for name in child.named_children(&mut cursor) {
+                        let name = self.text(name);
+                        if child.kind() == "global_statement" {
+                            self.scope().globals.insert(name);
+                        } else {
+                            self.scope().nonlocals.insert(name);
+                        }
+                    }
+                }
+                "import_statement" | "import_from_statement" | "future_import_statement" => {
+                    let mut cursor = child.walk();
+                    for name in child.children_by_field_name("name", &mut cursor) {
+                        let bound = match name.child_by_field_name("alias") {
+                            Some(alias) => alias,
+                            None => name.named_child(0).unwrap_or(name),
+                        };
+                        let bound = self.text(bound);
+                        self.scope().bound.insert(bound);
+                    }
+                }
+                "except_clause" => {
+                    let mut cursor = child.walk();
+                    let parts: Vec<Node> = child
+                        .named_children(&mut cursor)
+                        .filter(|c| c.kind() != "block")
+                        .collect();
+                    if let Some(target) = parts.get(1) {
+                        self.bind(*target);
+                    }
+                    self.bindings(child);
+                }
+                "with_item" => {
+                    if let Some(alias) = child.child_by_field_name("alias") {
+                        self.bind(alias);
+                    }
+                    self.bindings(child);
+                }
+                "named_expression" => {
+                    if let Some(name) = child.child_by_field_name("name") {
+                        self.bind(name);
+                    }
+                    self.bindings(child);
+                }
+                "assignment" | "augmented_assignment" | "for_statement" => {
+                    if let Some(left) = child.child_by_field_name("left") {
+                        self.bind(left);
+                    }
+                    self.bindings(child);
+                }
+                "delete_statement" => {
+                    let mut cursor = child.walk();
+                    for target in child.named_children(&mut cursor) {
+                        self.bind(target);
+                    }
+                }
+                _ => self.bindings(child),
+            }
+        }
+    }
+
+    /// `:=` inside a comprehension binds in the scope around it.
+    fn walrus(&mut self, node: Node) {
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor) {
+            match child.kind() {
+                "named_expression" => {
+                    if let Some(name) = child.child_by_field_name("name") {
+                        self.bind(name);
+                    }
+                    self.walrus(child);
+                }
+                "lambda" | "function_definition" | "class_definition" => {}
+                _ => self.walrus(child),
+            }
+        }
+    }
+
+    fn bind(&mut self, target: Node) {
+        match target.kind() {
+            "identifier" => {
+                let name = self.text(target);
+                self.scope().bound.insert(name);
+            }
+            kind if PATTERNS.contains(&kind) => {
+                let mut cursor = target.walk();
+                for child in target.named_children(&mut cursor) {
+                    self.bind(child);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes of an assignment target, and reads like `x` in `x.a = ..` or
+    /// `x[i] = ..`.
+    fn targets(&mut self, target: Node) {
+        match target.kind() {
+            "identifier" => self.write(target),
+            kind if PATTERNS.contains(&kind) => {
+                let mut cursor = target.walk();
+                for child in target.named_children(&mut cursor) {
+                    self.targets(child);
+                }
+            }
+            _ => {
+                self.mutate(target);
+                self.walk(target);
+            }
+        }
+    }
+
+    fn deleted(&mut self, target: Node) {
+        match target.kind() {
+            "identifier" => self.record(target, Access::Unsupported("by `del`")),
+            kind if PATTERNS.contains(&kind) => {
+                let mut cursor = target.walk();
+                for child in target.named_children(&mut cursor) {
+                    self.deleted(child);
+                }
+            }
+            _ => {
+                self.mutate(target);
+                self.walk(target);
+            }
+        }
+    }
+
+    fn walk_children(&mut self, node: Node) {
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor) {
+            self.walk(child);
+        }
+    }
+
+    fn walk_field(&mut self, node: Node, field: &str) {
+        let mut cursor = node.walk();
+        for child in node.children_by_field_name(field, &mut cursor) {
+            self.walk(child);
+        }
+    }
+
+    fn walk(&mut self, node: Node) {
+        match node.kind() {
+            "identifier" => self.read(node),
+            "assignment" => {
+                self.walk_field(node, "right");
+                // annotations of locals are never evaluated in a function
+                if self.scope().kind == Kind::Class {
+                    self.walk_field(node, "type");
+                }
+                if let Some(left) = node.child_by_field_name("left") {
+                    self.targets(left);
+                }
+            }
+            "for_statement" => {
+                self.walk_field(node, "right");
+                if let Some(left) = node.child_by_field_name("left") {
+                    self.targets(left);
+                }
+                self.walk_field(node, "body");
+                self.walk_field(node, "alternative");
+            }
+            "with_item" => {
+                self.walk_field(node, "value");
+                if let Some(alias) = node.child_by_field_name("alias") {
+                    self.targets(alias);
+                }
+            }
+            "except_clause" => {
+                let mut cursor = node.walk();
+                let parts: Vec<Node> = node.named_children(&mut cursor).collect();
+                for (i, part) in parts.into_iter().enumerate() {
+                    if i == 1 && part.kind() != "block" {
+                        self.targets(part);
+                    } else {
+                        self.walk(part);
+                    }
+                }
+            }
+            "delete_statement" => {
+                let mut cursor = node.walk();
+                for target in node.named_children(&mut cursor) {
+                    self.deleted(target);
+                }
+            }
+            "named_expression" => {
+                self.walk_field(node, "value");
+                if let Some(name) = node.child_by_field_name("name") {
+                    self.write(name);
+                }
+            }
+            "augmented_assignment" => match node.child_by_field_name("left") {
+                Some(left) if left.kind() == "identifier" => {
+                    self.walk_field(node, "right");
+                    let name = self.text(left);
+                    self.record(left, Access::Unsupported("in place"));
+                    if self.is_global(&name) && !BUILTINS.contains(&name.as_str()) {
+                        self.analysis.requires.insert(name);
+                    }
+                }
+                left => {
+                    if let Some(left) = left {
+                        self.mutate(left);
+                    }
+                    self.walk_children(node);
+                }
+            },
+            "call" => {
+                self.mutate(node);
+                self.walk_children(node);
+            }
+            "import_statement" | "import_from_statement" => {
+                let mut cursor = node.walk();
+                for name in node.children_by_field_name("name", &mut cursor) {
+                    if let Some(alias) = name.child_by_field_name("alias") {
+                        self.write(alias);
+                    } else if name.named_child_count() != 1 {
+                        // `import a.b` binds `a`, which can't be aliased
+                        if let Some(package) = name.named_child(0) {
+                            self.record(package, Access::Unsupported("by a package import"));
+                        }
+                    } else if self.scopes.len() == 1 && !conditional(name) {
+                        self.record(name, Access::Import);
+                    } else {
+                        self.write(name);
+                    }
+                }
+            }
+            "future_import_statement" | "global_statement" | "nonlocal_statement" => {}
+            "attribute" => self.walk_field(node, "object"),
+            "keyword_argument" => self.walk_field(node, "value"),
+            "function_definition" | "lambda" => {
+                let mut params = IndexSet::default();
+                if let Some(parameters) = node.child_by_field_name("parameters") {
+                    self.parameters(parameters, &mut params);
+                }
+                self.walk_field(node, "return_type");
+                if let Some(name) = node.child_by_field_name("name") {
+                    self.write(name);
+                }
+                if let Some(body) = node.child_by_field_name("body") {
+                    self.enter(Kind::Function, params, body);
+                    if body.kind() == "block" {
+                        self.walk_children(body);
+                    } else {
+                        self.walk(body);
+                    }
+                    self.scopes.pop();
+                }
+            }
+            "class_definition" => {
+                self.walk_field(node, "superclasses");
+                if let Some(name) = node.child_by_field_name("name") {
+                    self.write(name);
+                }
+                if let Some(body) = node.child_by_field_name("body") {
+                    self.enter(Kind::Class, Default::default(), body);
+                    self.walk_children(body);
+                    self.scopes.pop();
+                }
+            }
+            kind if COMPREHENSIONS.contains(&kind) => {
+                // the first iterable is evaluated outside the comprehension
+                let mut cursor = node.walk();
+                let parts: Vec<Node> = node.named_children(&mut cursor).collect();
+                let mut targets = IndexSet::default();
+                for (i, part) in parts.iter().enumerate() {
+                    if part.kind() == "for_in_clause" {
+                        if i == 1 {
+                            self.walk_field(*part, "right");
+                        }
+                        if let Some(left) = part.child_by_field_name("left") {
+                            targets.extend(identifiers(left).into_iter().map(|n| self.text(n)));
+                        }
+                    }
+                }
+                self.scopes.push(Scope::new(Kind::Function, targets));
+                for (i, part) in parts.into_iter().enumerate() {
+                    if part.kind() == "for_in_clause" {
+                        if i != 1 {
+                            self.walk_field(part, "right");
+                        }
+                        if let Some(left) = part.child_by_field_name("left") {
+                            self.targets(left);
+                        }
+                    } else {
+                        self.walk(part);
+                    }
+                }
+                self.scopes.pop();
+            }
+            _ => self.walk_children(node),
+        }
+    }
+
+    /// Defaults and annotations are read where the function is defined, the
+    /// parameter names are bound in its body.
+    fn parameters(&mut self, params: Node, bound: &mut IndexSet<String>) {
+        let mut cursor = params.walk();
+        for param in params.named_children(&mut cursor) {
+            match param.kind() {
+                "identifier" => {
+                    bound.insert(self.text(param));
+                }
+                "default_parameter" | "typed_default_parameter" => {
+                    if let Some(name) = param.child_by_field_name("name") {
+                        bound.insert(self.text(name));
+                    }
+                    self.walk_field(param, "type");
+                    self.walk_field(param, "value");
+                }
+                _ => {
+                    for name in identifiers(param) {
+                        bound.insert(self.text(name));
+                    }
+                    self.walk_field(param, "type");
+                }
+            }
+        }
+    }
+}
+
+/// Plain names bound by a target or parameter.
+pub fn identifiers(target: Node) -> Vec<Node> {
+    match target.kind() {
+        "identifier" => vec![target],
+        kind if PATTERNS.contains(&kind)
+            || matches!(kind, "typed_parameter" | "dictionary_splat_pattern") =>
+        {
+            let mut cursor = target.walk();
+            target
+                .named_children(&mut cursor)
+                .flat_map(identifiers)
+                .collect()
+        }
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requires(code: &str) -> Vec<String> {
+        analyze(code).unwrap().requires.into_iter().collect()
+    }
+
+    #[test]
+    fn comprehensions() {
+        assert_eq!(requires("ys = [x for x in xs if x > k]"), ["xs", "k"]);
+        // the first iterable is read outside of the comprehension
+        assert_eq!(requires("ys = [y for y in y]"), ["y"]);
+        assert_eq!(requires("d = {k: v for k, v in items}"), ["items"]);
+    }
+
+    #[test]
+    fn global_and_nonlocal() {
+        assert_eq!(
+            requires("def f():\n    global g\n    g = 1\n    return g"),
+            ["g"]
+        );
+        assert_eq!(
+            requires("def f():\n    a = 1\n    def g():\n        nonlocal a\n        return a\n    return g"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn del() {
+        assert!(requires("del x").is_empty());
+        assert_eq!(requires("del x[0]"), ["x"]);
+    }
+
+    #[test]
+    fn walrus() {
+        assert_eq!(requires("if (n := len(a)) > 1:\n    print(n)"), ["a"]);
+        // binds in the scope around the comprehension
+        assert_eq!(
+            requires("ys = [(y := f(x)) for x in xs]\nz = y"),
+            ["xs", "f"]
+        );
+    }
+
+    #[test]
+    fn with_and_except() {
+        assert_eq!(requires("with open(p) as fh:\n    data = fh.read()"), ["p"]);
+        assert_eq!(
+            requires("try:\n    pass\nexcept E as e:\n    print(e)"),
+            ["E"]
+        );
+    }
+
+    #[test]
+    fn nested_scopes() {
+        assert_eq!(requires("def f(a, c=d):\n    return a + b"), ["d", "b"]);
+        // class bodies are not visible from their methods
+        assert_eq!(
+            requires("class C:\n    k = 1\n    def m(self):\n        return k\nc = C()"),
+            ["k"]
+        );
+        assert_eq!(requires("f = lambda x: x + y"), ["y"]);
+    }
+
+    #[test]
+    fn builtins() {
+        assert_eq!(requires("n = len(xs)\nm = anext(it)"), ["xs", "it"]);
+        assert!(requires("len = 3\nprint(len)").is_empty());
+    }
+
+    #[test]
+    fn uses() {
+        let analysis = analyze("x = x + 1\nxs.append(x)\nfor i in xs:\n    y = i").unwrap();
+        let uses: Vec<_> = analysis
+            .uses
+            .iter()
+            .map(|u| (u.name.as_str(), u.access))
+            .collect();
+        assert_eq!(
+            uses,
+            [
+                ("x", Access::Read),
+                ("x", Access::Write),
+                ("xs", Access::Mutate),
+                ("x", Access::Read),
+                ("xs", Access::Read),
+                ("i", Access::Write),
+                ("i", Access::Read),
+                ("y", Access::Unsupported("conditionally")),
+            ]
+        );
+        assert_eq!(analysis.mutates.keys().collect::<Vec<_>>(), ["xs"]);
+    }
+
+    #[test]
+    fn changes() {
+        let mutates =
+            analyze("a.x = 1\nb[0] += 1\nc.append(1)\nd.drop(0, inplace=True)\ne.sort_values()")
+                .unwrap()
+                .mutates;
+        let guessed: Vec<_> = mutates
+            .iter()
+            .map(|(var, c)| (var.as_str(), c.guessed))
+            .collect();
+        assert_eq!(
+            guessed,
+            [("a", false), ("b", false), ("c", true), ("d", false)]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            analyze("x = (").unwrap_err().kind(),
+            ErrorKind::InvalidSyntax { .. }
+        ));
+        assert!(matches!(
+            analyze("from os import *").unwrap_err().kind(),
+            ErrorKind::WildcardImport { .. }
+        ));
+    }
+}
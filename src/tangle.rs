@@ -1,5 +1,8 @@
 use std::{default, iter::FromIterator, ops::Sub, vec};
 
+use crate::interchange::SchemaError;
+use crate::queries::QueryPack;
+use crate::scope;
 pub use indexmap::{IndexMap, IndexSet};
 use numpy::ndarray::s;
 use serde::{de::IntoDeserializer, Deserialize, Serialize};
@@ -12,19 +15,81 @@ pub enum Tangle {
         nodes: IndexMap<String, Tangle>,
         provides: IndexSet<String>,
         requires: IndexSet<String>,
+        /// type annotations of `requires` and `provides`, by variable
+        #[serde(default)]
+        annotations: IndexMap<String, String>,
+        /// stays the same when the flow is renamed, see `Tangle::id`
+        #[serde(default = "fresh_id")]
+        id: String,
+        #[serde(default)]
+        trivia: Trivia,
     },
     Leaf {
         name: String,
         provides: IndexSet<String>,
         requires: IndexSet<String>,
         code: String,
+        #[serde(default)]
+        annotations: IndexMap<String, String>,
+        /// stays the same when the leaf is renamed, see `Tangle::id`
+        #[serde(default = "fresh_id")]
+        id: String,
+        /// parameters with a default value, by name, these are constants
+        /// rather than edges of the graph
+        #[serde(default)]
+        defaults: IndexMap<String, String>,
+        #[serde(default)]
+        trivia: Trivia,
     },
 }
 
+/// Source text around a function that is not part of the graph, kept so that
+/// parsing and emitting a file loses nothing.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Trivia {
+    /// module code between the previous function and this one
+    pub before: String,
+    pub decorators: Vec<String>,
+    /// flow body code above each node call, by node
+    pub node_before: IndexMap<String, String>,
+    /// rest of the line of each node call, by node
+    pub node_inline: IndexMap<String, String>,
+    /// flow body code between the last node call and the `return`
+    pub before_return: String,
+    /// code after the `return`, starting with the rest of its line
+    pub after_return: String,
+    /// module code after the last function
+    pub after: String,
+    /// where the function is defined in the parsed file
+    #[serde(skip)]
+    pub span: Option<Span>,
+}
+
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 static NAMER: std::sync::atomic::AtomicUsize = AtomicUsize::new(0);
 
+const ID_MARKER: &str = "# tangle-id:";
+
+/// A random id for a node that has never been saved.
+pub fn fresh_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_usize(NAMER.fetch_add(1, Ordering::SeqCst));
+    format!("{:016x}", hasher.finish())
+}
+
+/// The id of a node loaded from code without an id marker, derived from its
+/// name so reloading the same file gives the same ids. This is the 64 bit
+/// FNV-1a hash of the name, which unlike `DefaultHasher` never changes.
+pub(crate) fn id_for_name(name: &str) -> String {
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
 impl Default for Tangle {
     fn default() -> Self {
         let name = NAMER.fetch_add(1, Ordering::SeqCst);
@@ -33,6 +98,10 @@ impl Default for Tangle {
             provides: Default::default(),
             requires: Default::default(),
             code: "".to_string(),
+            annotations: Default::default(),
+            id: fresh_id(),
+            defaults: Default::default(),
+            trivia: Default::default(),
         }
     }
 }
@@ -45,6 +114,26 @@ impl Tangle {
         }
     }
 
+    /// Stable identity of a node, independent of its Python name.
+    pub fn id(&self) -> &String {
+        match self {
+            Self::Dataflow { id, .. } | Self::Leaf { id, .. } => id,
+        }
+    }
+
+    /// Renames the node, not the entry of a flow holding it.
+    pub fn set_name(&mut self, new_name: String) {
+        match self {
+            Self::Dataflow { name, .. } | Self::Leaf { name, .. } => *name = new_name,
+        }
+    }
+
+    pub fn set_id(&mut self, new_id: String) {
+        match self {
+            Self::Dataflow { id, .. } | Self::Leaf { id, .. } => *id = new_id,
+        }
+    }
+
     pub fn deps(&self) -> (&IndexSet<String>, &IndexSet<String>) {
         match self {
             Self::Dataflow {
@@ -62,57 +151,594 @@ impl Tangle {
             Self::Dataflow { .. } => panic!(),
         }
     }
+
+    pub fn annotations(&self) -> &IndexMap<String, String> {
+        match self {
+            Self::Dataflow { annotations, .. } => annotations,
+            Self::Leaf { annotations, .. } => annotations,
+        }
+    }
+
+    pub fn annotation(&self, var: &str) -> Option<&String> {
+        self.annotations().get(var)
+    }
+
+    /// Default values of the constants of this node. Those of the leaves of a
+    /// flow are prefixed by the leaf name, like `node_2_scale`, and become
+    /// parameters of the flow function so a caller can override them.
+    pub fn defaults(&self) -> IndexMap<String, String> {
+        match self {
+            Self::Leaf { defaults, .. } => defaults.clone(),
+            Self::Dataflow { nodes, .. } => {
+                nodes.values().flat_map(Tangle::flow_defaults).collect()
+            }
+        }
+    }
+
+    /// The defaults of this node as parameters of the flow it is a node of.
+    fn flow_defaults(&self) -> Vec<(String, String)> {
+        match self {
+            Self::Leaf { name, defaults, .. } => defaults
+                .iter()
+                .map(|(p, v)| (format!("{}_{}", name, p), v.clone()))
+                .collect(),
+            Self::Dataflow { .. } => self.defaults().into_iter().collect(),
+        }
+    }
+
+    /// The annotations of the parameters with a default, by the names
+    /// `defaults` gives them.
+    fn default_annotations(&self) -> IndexMap<String, String> {
+        match self {
+            Self::Leaf {
+                defaults,
+                annotations,
+                ..
+            } => defaults
+                .keys()
+                .filter_map(|p| Some((p.clone(), annotations.get(p)?.clone())))
+                .collect(),
+            Self::Dataflow { nodes, .. } => nodes
+                .values()
+                .flat_map(|node| {
+                    let own = node.default_annotations().into_iter();
+                    match node {
+                        Self::Leaf { name, .. } => own
+                            .map(|(p, ty)| (format!("{}_{}", name, p), ty))
+                            .collect::<Vec<_>>(),
+                        Self::Dataflow { .. } => own.collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn defaults_mut(&mut self) -> Option<&mut IndexMap<String, String>> {
+        match self {
+            Self::Leaf { defaults, .. } => Some(defaults),
+            Self::Dataflow { .. } => None,
+        }
+    }
+
+    /// Sets a default by the name `defaults` gives it.
+    pub fn set_default(&mut self, param: &str, value: String) {
+        match self {
+            Self::Leaf { defaults, .. } => {
+                if let Some(v) = defaults.get_mut(param) {
+                    *v = value;
+                }
+            }
+            Self::Dataflow { nodes, .. } => {
+                for node in nodes.values_mut() {
+                    match node {
+                        Self::Leaf { name, defaults, .. } => {
+                            let own = param
+                                .strip_prefix(name.as_str())
+                                .and_then(|p| p.strip_prefix('_'));
+                            if let Some(v) = own.and_then(|p| defaults.get_mut(p)) {
+                                *v = value.clone();
+                            }
+                        }
+                        Self::Dataflow { .. } => node.set_default(param, value.clone()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Required variables the node changes in place, with where it does so.
+    pub fn mutations(&self) -> IndexMap<String, scope::Change> {
+        match self {
+            Tangle::Leaf { code, .. } => scope::analyze(code)
+                .map(|analysis| analysis.mutates)
+                .unwrap_or_default(),
+            Tangle::Dataflow {
+                nodes, requires, ..
+            } => nodes
+                .values()
+                .flat_map(|node| node.mutations())
+                .filter(|(var, _)| requires.contains(var))
+                .collect(),
+        }
+    }
+
+    /// Copies the defaults of `other` for the variables `self` requires, they
+    /// stop being requirements. Cells are re-parsed from code that has no
+    /// signature to keep them in.
+    pub fn keep_defaults(&mut self, other: &Tangle) {
+        if let (
+            Self::Leaf {
+                requires, defaults, ..
+            },
+            Self::Leaf { defaults: kept, .. },
+        ) = (self, other)
+        {
+            for (param, value) in kept {
+                if requires.shift_remove(param) || defaults.contains_key(param) {
+                    defaults.insert(param.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    pub fn trivia(&self) -> &Trivia {
+        match self {
+            Self::Dataflow { trivia, .. } | Self::Leaf { trivia, .. } => trivia,
+        }
+    }
+
+    pub fn keep_trivia(&mut self, other: &Tangle) {
+        match self {
+            Self::Dataflow { trivia, .. } | Self::Leaf { trivia, .. } => {
+                *trivia = other.trivia().clone()
+            }
+        }
+    }
+
+    /// Copies the annotations of `other` for the variables `self` still uses,
+    /// cells are re-parsed from code that has no signature to annotate.
+    pub fn keep_annotations(&mut self, other: &Tangle) {
+        let kept = {
+            let (requires, provides) = self.deps();
+            other
+                .annotations()
+                .iter()
+                .filter(|(v, _)| requires.contains(*v) || provides.contains(*v))
+                .map(|(v, ty)| (v.clone(), ty.clone()))
+                .collect::<Vec<_>>()
+        };
+        match self {
+            Self::Dataflow { annotations, .. } | Self::Leaf { annotations, .. } => {
+                annotations.extend(kept)
+            }
+        }
+    }
+}
+
+/// Where in the parsed file an error comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Span {
+    pub bytes: std::ops::Range<usize>,
+    pub start: tree_sitter::Point,
+    pub end: tree_sitter::Point,
 }
 
+impl From<Node<'_>> for Span {
+    fn from(node: Node) -> Self {
+        Self {
+            bytes: node.byte_range(),
+            start: node.start_position(),
+            end: node.end_position(),
+        }
+    }
+}
+
+impl Span {
+    /// The span of `bytes` in `text`, for files tree-sitter did not parse.
+    pub fn in_text(text: &str, bytes: std::ops::Range<usize>) -> Self {
+        let point = |at: usize| {
+            let before = &text[..at];
+            let row = before.matches('\n').count();
+            let column = at - before.rfind('\n').map_or(0, |n| n + 1);
+            tree_sitter::Point { row, column }
+        };
+        Self {
+            start: point(bytes.start),
+            end: point(bytes.end),
+            bytes,
+        }
+    }
+}
+
+/// What went wrong, see `Error`.
 #[derive(Debug)]
-pub enum Error {
-    MissingLeaf(String),
-    NoDataflowFound,
+pub enum ErrorKind {
+    MissingLeaf {
+        name: String,
+        span: Span,
+    },
+    RecursiveFlow {
+        name: String,
+        span: Span,
+    },
+    NoDataflowFound {
+        span: Span,
+    },
     RequiresMismatch {
         expected: IndexSet<String>,
         found: IndexSet<String>,
+        span: Span,
     },
     ProvidesMismatch {
         expected: IndexSet<String>,
         found: IndexSet<String>,
+        span: Span,
+    },
+    CircularMerge {
+        forward: IndexSet<String>,
+        backward: IndexSet<String>,
+        span: Option<Span>,
+    },
+    ConflictingMerge {
+        provides: IndexSet<String>,
+        nodes: IndexSet<String>,
+        span: Option<Span>,
+    },
+    Cycle {
+        cycles: Vec<Cycle>,
+        span: Option<Span>,
+    },
+    ConflictingProviders {
+        var: String,
+        nodes: IndexSet<String>,
+        span: Option<Span>,
+    },
+    UnknownVariable {
+        var: String,
+        span: Option<Span>,
+    },
+    /// the flow parameter of a default is also that of another default, or a
+    /// variable of the flow
+    ConflictingDefaults {
+        param: String,
+        nodes: IndexSet<String>,
+        span: Option<Span>,
+    },
+    UnknownDefault {
+        node: String,
+        param: String,
+        span: Span,
+    },
+    UnsupportedReturn {
+        name: String,
+        span: Span,
+    },
+    InconsistentReturns {
+        name: String,
+        expected: IndexSet<String>,
+        found: IndexSet<String>,
+        span: Span,
+    },
+    InvalidSyntax {
+        span: Span,
+    },
+    WildcardImport {
+        span: Span,
+    },
+    /// the producer and a consumer of `var` annotate it with different
+    /// types, `span` is the consumer's
+    TypeMismatch {
+        var: String,
+        provider: String,
+        provided: String,
+        consumer: String,
+        required: String,
+        span: Option<Span>,
+    },
+    /// a leaf with `await` in a flow emitted without `async`
+    SyncAwait {
+        name: String,
+        span: Option<Span>,
+    },
+    /// `span` is in the query file, `None` when it can't be read
+    InvalidQuery {
+        file: String,
+        message: String,
+        span: Option<Span>,
     },
+    MissingCapture {
+        file: String,
+        capture: String,
+        span: Span,
+    },
+    UnknownCapture {
+        file: String,
+        capture: String,
+        span: Span,
+    },
+    /// spans are in the JSON text
+    InvalidGraph(Vec<SchemaError>),
 }
 
-lazy_static! {
-    static ref QUERY: tree_sitter::Query = {
-        tree_sitter::Query::new(tree_sitter_python::language(), include_str!("query.scm")).unwrap()
-    };
-    static ref CAPTURE_NAMES: &'static [String] = QUERY.capture_names();
-    static ref LEAF_NAME: u32 = QUERY.capture_index_for_name("leaf.name").unwrap();
-    static ref LEAF_PARAM: u32 = QUERY.capture_index_for_name("leaf.param").unwrap();
-    static ref LEAF_BODY: u32 = QUERY.capture_index_for_name("leaf.body").unwrap();
-    static ref LEAF_PROVIDES: u32 = QUERY.capture_index_for_name("leaf.provides").unwrap();
-    // static ref FLOW_NAME: u32 = QUERY.capture_index_for_name("flow.name").unwrap();
-    // static ref FLOW_PARAM: u32 = QUERY.capture_index_for_name("flow.param").unwrap();
-    // static ref FLOW_PROVIDES: u32 = QUERY.capture_index_for_name("flow.provides").unwrap();
-    static ref FLOW_NODE: u32 = QUERY.capture_index_for_name("flow.node").unwrap();
-    // static ref FLOW_NODE_NAME: u32 = QUERY.capture_index_for_name("flow.node.name").unwrap();
-    // static ref FLOW_NODE_PARAM: u32 = QUERY.capture_index_for_name("flow.node.arg").unwrap();
-    // static ref FLOW_NODE_PROVIDES: u32 =
-    //     QUERY.capture_index_for_name("flow.node.provides").unwrap();
-
-    static ref NODE_QUERY: tree_sitter::Query = {
-        tree_sitter::Query::new(tree_sitter_python::language(), include_str!("node_query.scm")).unwrap()
-    };
+/// An error with where it comes from. Spans of graph errors are in the file
+/// the graph was parsed from, and missing for graphs built in the editor.
+#[derive(Debug)]
+pub struct Error(Box<ErrorKind>);
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error(Box::new(kind))
+    }
+}
 
-    static ref FLOW_NODE_NAME: u32 = NODE_QUERY.capture_index_for_name("flow.node.name").unwrap();
-    static ref FLOW_NODE_PARAM: u32 = NODE_QUERY.capture_index_for_name("flow.node.param").unwrap();
-    static ref FLOW_NODE_PROVIDES: u32 =
-    NODE_QUERY.capture_index_for_name("flow.node.provides").unwrap();
+fn join(vars: &IndexSet<String>) -> String {
+    vars.iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
 
-    static ref CELL_QUERY: tree_sitter::Query = {
-        tree_sitter::Query::new(tree_sitter_python::language(), include_str!("cell_query.scm")).unwrap()
-    };
-    static ref CELL_FUNCDEF: u32 = CELL_QUERY.capture_index_for_name("function").unwrap();
-    static ref CELL_VARIABLE: u32 = CELL_QUERY.capture_index_for_name("variable").unwrap();
-    static ref CELL_IMPORT: u32 = CELL_QUERY.capture_index_for_name("import").unwrap();
-    static ref CELL_IDENTIFIER: u32 = CELL_QUERY.capture_index_for_name("identifier").unwrap();
-    static ref CELL_ERROR: u32 = CELL_QUERY.capture_index_for_name("error").unwrap();
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind() {
+            ErrorKind::MissingLeaf { name, .. } => write!(f, "no leaf or flow named `{}`", name),
+            ErrorKind::RecursiveFlow { name, .. } => write!(f, "flow `{}` calls itself", name),
+            ErrorKind::NoDataflowFound { .. } => write!(f, "no dataflow found"),
+            ErrorKind::RequiresMismatch {
+                expected, found, ..
+            } => write!(
+                f,
+                "called with ({}) but the definition requires ({})",
+                join(expected),
+                join(found)
+            ),
+            ErrorKind::ProvidesMismatch {
+                expected, found, ..
+            } => write!(
+                f,
+                "assigns [{}] but the definition returns [{}]",
+                join(expected),
+                join(found)
+            ),
+            ErrorKind::CircularMerge {
+                forward, backward, ..
+            } => write!(
+                f,
+                "flows depend on each other through [{}] and [{}]",
+                join(forward),
+                join(backward)
+            ),
+            ErrorKind::ConflictingMerge {
+                provides, nodes, ..
+            } => write!(
+                f,
+                "both flows provide [{}] or define nodes [{}]",
+                join(provides),
+                join(nodes)
+            ),
+            ErrorKind::Cycle { cycles, .. } => {
+                write!(f, "dependency cycle")?;
+                for cycle in cycles {
+                    write!(
+                        f,
+                        "\n  between nodes [{}] through [{}]",
+                        join(&cycle.nodes),
+                        join(&cycle.vars)
+                    )?;
+                }
+                Ok(())
+            }
+            ErrorKind::ConflictingProviders { var, nodes, .. } => {
+                write!(f, "`{}` is provided by several nodes: {}", var, join(nodes))
+            }
+            ErrorKind::UnknownVariable { var, .. } => write!(f, "no node provides `{}`", var),
+            ErrorKind::ConflictingDefaults { param, nodes, .. } => write!(
+                f,
+                "the defaults of {} are all passed as `{}`, or it is also a variable, rename one",
+                join(nodes),
+                param
+            ),
+            ErrorKind::UnknownDefault { node, param, .. } => {
+                write!(f, "`{}` has no parameter `{}` with a default", node, param)
+            }
+            ErrorKind::UnsupportedReturn { name, .. } => write!(
+                f,
+                "`{}` has to return variables, like `return x`, `return x, y` or `return [x, y]`",
+                name
+            ),
+            ErrorKind::InconsistentReturns {
+                name,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "`{}` returns [{}] here but [{}] at its end",
+                name,
+                join(found),
+                join(expected)
+            ),
+            ErrorKind::InvalidSyntax { .. } => write!(f, "invalid syntax"),
+            ErrorKind::WildcardImport { .. } => write!(
+                f,
+                "`import *` can't be used in a node, import the names it uses instead"
+            ),
+            ErrorKind::TypeMismatch {
+                var,
+                provider,
+                provided,
+                consumer,
+                required,
+                ..
+            } => write!(
+                f,
+                "`{}` is `{}` in {} but `{}` in {}",
+                var, provided, provider, required, consumer
+            ),
+            ErrorKind::SyncAwait { name, .. } => write!(
+                f,
+                "`{}` awaits, so the flow only runs when exported as async",
+                name
+            ),
+            ErrorKind::InvalidQuery { file, message, .. } => write!(f, "{}: {}", file, message),
+            ErrorKind::MissingCapture { file, capture, .. } => {
+                write!(f, "{} has to capture `@{}`", file, capture)
+            }
+            ErrorKind::UnknownCapture { file, capture, .. } => write!(
+                f,
+                "{} captures unknown `@{}`, name captures for predicates `@_..`",
+                file, capture
+            ),
+            ErrorKind::InvalidGraph(errors) => {
+                write!(f, "invalid tangle graph")?;
+                for err in errors {
+                    write!(f, "\n  at {}", err)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.0
+    }
+
+    pub fn into_kind(self) -> ErrorKind {
+        *self.0
+    }
+
+    pub fn span(&self) -> Option<&Span> {
+        match self.kind() {
+            ErrorKind::MissingLeaf { span, .. }
+            | ErrorKind::RecursiveFlow { span, .. }
+            | ErrorKind::NoDataflowFound { span }
+            | ErrorKind::RequiresMismatch { span, .. }
+            | ErrorKind::ProvidesMismatch { span, .. }
+            | ErrorKind::UnsupportedReturn { span, .. }
+            | ErrorKind::InconsistentReturns { span, .. }
+            | ErrorKind::InvalidSyntax { span }
+            | ErrorKind::WildcardImport { span }
+            | ErrorKind::MissingCapture { span, .. }
+            | ErrorKind::UnknownCapture { span, .. }
+            | ErrorKind::UnknownDefault { span, .. } => Some(span),
+            ErrorKind::CircularMerge { span, .. }
+            | ErrorKind::ConflictingMerge { span, .. }
+            | ErrorKind::Cycle { span, .. }
+            | ErrorKind::ConflictingProviders { span, .. }
+            | ErrorKind::UnknownVariable { span, .. }
+            | ErrorKind::ConflictingDefaults { span, .. }
+            | ErrorKind::TypeMismatch { span, .. }
+            | ErrorKind::SyncAwait { span, .. }
+            | ErrorKind::InvalidQuery { span, .. } => span.as_ref(),
+            ErrorKind::InvalidGraph(errors) => errors.first()?.span.as_ref(),
+        }
+    }
+
+    /// Renders the error in the style of rustc, with the offending code in
+    /// `code` underlined.
+    pub fn render(&self, code: &str, path: &str) -> String {
+        let mut out = format!("error: {}\n", self);
+        let span = match self.span() {
+            Some(span) => span,
+            None => return out,
+        };
+        let gutter = " ".repeat((span.end.row + 1).to_string().len());
+        out += &format!(
+            "{}--> {}:{}:{}\n{} |\n",
+            gutter,
+            path,
+            span.start.row + 1,
+            span.start.column + 1,
+            gutter
+        );
+        for (row, line) in code
+            .lines()
+            .enumerate()
+            .skip(span.start.row)
+            .take(span.end.row - span.start.row + 1)
+        {
+            let line = line.trim_end_matches('\r');
+            let from = if row == span.start.row {
+                span.start.column
+            } else {
+                line.len() - line.trim_start().len()
+            };
+            let to = if row == span.end.row {
+                span.end.column
+            } else {
+                line.len()
+            };
+            out += &format!(
+                "{:>width$} | {}\n{} | {}{}\n",
+                row + 1,
+                line,
+                gutter,
+                " ".repeat(from),
+                "^".repeat(to.saturating_sub(from).max(1)),
+                width = gutter.len()
+            );
+        }
+        out
+    }
+}
+
+/// Reads a command line value of `emit_script` as a Python literal, falling
+/// back to the plain string.
+const SCRIPT_VALUE: &str = r#"def _value(text):
+    import ast
+
+    try:
+        return ast.literal_eval(text)
+    except (ValueError, SyntaxError):
+        return text
+"#;
+
+/// Stands in for the `gui` of the editor in an exported script.
+const HEADLESS_GUI: &str = r#"class _HeadlessGui:
+    """Buttons are never pressed, sliders stay at 0, everything else does nothing."""
+
+    def button(self, label):
+        return False
+
+    def slider(self, label):
+        return 0.0
+
+    def __getattr__(self, name):
+        return lambda *args, **kwargs: None
+
+"#;
+
+const SCRIPT_OUTPUT: &str = r#"    if args._out:
+        import pickle
+
+        with open(args._out, "wb") as f:
+            pickle.dump(results, f)
+    else:
+        for name, value in results.items():
+            print(f"{name} = {value!r}")"#;
+
+/// Whether `node` binds a name of the cell itself rather than of a function,
+/// class, lambda or comprehension in it. `:=` binds outside comprehensions.
+fn binds_in_cell(node: Node, walrus: bool) -> bool {
+    let mut parent = node.parent();
+    while let Some(p) = parent {
+        match p.kind() {
+            "function_definition" | "class_definition" | "lambda" => return false,
+            "list_comprehension"
+            | "set_comprehension"
+            | "dictionary_comprehension"
+            | "generator_expression"
+                if !walrus =>
+            {
+                return false
+            }
+            _ => {}
+        }
+        parent = p.parent();
+    }
+    true
 }
 
 #[derive(Clone, Debug, Default)]
@@ -137,10 +763,24 @@ fn parse_id_or_seq(capture_idx: u32, qm: &QueryMatch, code: &TangleString) -> In
         if node.kind() == "list"
             || node.kind() == "parameters"
             || node.kind() == "list_pattern"
+            || node.kind() == "pattern_list"
+            || node.kind() == "tuple_pattern"
             || node.kind() == "argument_list"
         {
             let mut cursor = node.walk();
             node.named_children(&mut cursor)
+                // constants, see `parse_defaults`
+                .filter(|n| {
+                    !matches!(
+                        n.kind(),
+                        "default_parameter" | "typed_default_parameter" | "keyword_argument"
+                    )
+                })
+                .map(|n| match n.kind() {
+                    // the annotation is read by `parse_annotations`
+                    "typed_parameter" => n.named_child(0).unwrap_or(n),
+                    _ => n,
+                })
                 .map(|n| code.0[n.byte_range()].to_string())
                 .collect()
         } else {
@@ -153,45 +793,332 @@ fn parse_id_or_seq(capture_idx: u32, qm: &QueryMatch, code: &TangleString) -> In
     }
 }
 
+/// `name: value` of the parameters with a default, or of the keyword
+/// arguments of a call.
+fn parse_defaults(
+    capture_idx: u32,
+    qm: &QueryMatch,
+    code: &TangleString,
+) -> IndexMap<String, String> {
+    let node = match qm.nodes_for_capture_index(capture_idx).next() {
+        Some(node) => node,
+        None => return Default::default(),
+    };
+    let text = |n: Node| code.0[n.byte_range()].to_string();
+    let mut cursor = node.walk();
+    node.named_children(&mut cursor)
+        .filter(|n| {
+            matches!(
+                n.kind(),
+                "default_parameter" | "typed_default_parameter" | "keyword_argument"
+            )
+        })
+        .filter_map(|n| {
+            let name = n.child_by_field_name("name")?;
+            let value = n.child_by_field_name("value")?;
+            Some((text(name), text(value)))
+        })
+        .collect()
+}
+
+/// Splits `Tuple[int, str]` into one annotation per returned variable.
+fn split_return_annotation(annotation: &str, count: usize) -> Vec<String> {
+    if count == 1 {
+        return vec![annotation.to_string()];
+    }
+    let inner = ["Tuple[", "tuple[", "List[", "list["]
+        .iter()
+        .find_map(|prefix| annotation.strip_prefix(prefix))
+        .and_then(|rest| rest.strip_suffix(']'));
+    let inner = match inner {
+        Some(inner) => inner,
+        None => return vec![],
+    };
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(inner[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(inner[start..].trim().to_string());
+    if parts.len() == count {
+        parts
+    } else {
+        vec![]
+    }
+}
+
+fn parse_annotations(
+    pack: &QueryPack,
+    qm: &QueryMatch,
+    code: &TangleString,
+    provides: &IndexSet<String>,
+) -> IndexMap<String, String> {
+    let mut annotations: IndexMap<String, String> = Default::default();
+    let params = match qm.nodes_for_capture_index(pack.leaf_param).next() {
+        Some(params) => params,
+        None => return annotations,
+    };
+    let text = |n: Node| code.0[n.byte_range()].to_string();
+    let mut cursor = params.walk();
+    for param in params.named_children(&mut cursor) {
+        if param.kind() == "typed_parameter" {
+            if let Some((name, ty)) = param.named_child(0).zip(param.child_by_field_name("type")) {
+                annotations.insert(text(name), text(ty));
+            }
+        } else if param.kind() == "typed_default_parameter" {
+            let name = param.child_by_field_name("name");
+            if let Some((name, ty)) = name.zip(param.child_by_field_name("type")) {
+                annotations.insert(text(name), text(ty));
+            }
+        }
+    }
+    if let Some(ret) = params
+        .parent()
+        .and_then(|f| f.child_by_field_name("return_type"))
+    {
+        let ret = text(ret);
+        let ret = ret.trim_matches('"');
+        for (var, ty) in provides
+            .iter()
+            .zip(split_return_annotation(ret, provides.len()))
+        {
+            annotations.insert(var.clone(), ty);
+        }
+    }
+    annotations
+}
+
+/// `def name(x: float, y) -> float:`
+fn emit_head(
+    name: &str,
+    requires: &IndexSet<String>,
+    defaults: &IndexMap<String, String>,
+    provides: &IndexSet<String>,
+    annotations: &IndexMap<String, String>,
+) -> String {
+    let params = requires
+        .iter()
+        .map(|r| match annotations.get(r) {
+            Some(ty) => format!("{}: {}", r, ty),
+            None => r.clone(),
+        })
+        .chain(defaults.iter().map(|(p, v)| match annotations.get(p) {
+            Some(ty) => format!("{}: {} = {}", p, ty, v),
+            None => format!("{}={}", p, v),
+        }))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let returns = provides
+        .iter()
+        .map(|p| annotations.get(p))
+        .collect::<Option<Vec<&String>>>();
+    match returns {
+        Some(returns) if returns.len() == 1 => {
+            format!("def {}({}) -> {}:", name, params, returns[0])
+        }
+        Some(returns) if returns.len() > 1 => format!(
+            "def {}({}) -> \"Tuple[{}]\":",
+            name,
+            params,
+            returns
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ),
+        _ => format!("def {}({}):", name, params),
+    }
+}
+
+fn line_start(code: &str, byte: usize) -> usize {
+    code[..byte].rfind('\n').map_or(0, |i| i + 1)
+}
+
+fn line_end(code: &str, byte: usize) -> usize {
+    code[byte..].find('\n').map_or(code.len(), |i| byte + i)
+}
+
+/// Whole lines of `code[bytes]` without the last newline, dedented by `by`.
+fn lines_between(code: &str, bytes: std::ops::Range<usize>, by: usize) -> String {
+    if bytes.start >= bytes.end {
+        return String::new();
+    }
+    let text = &code[bytes];
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    dedent(text.trim_end_matches('\r').to_string(), by)
+}
+
+fn indent(text: &str) -> String {
+    text.lines()
+        .map(|l| {
+            if l.trim().is_empty() {
+                String::new()
+            } else {
+                format!("    {}", l)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Variables returned by `return x`, `return x, y`, `return (x, y)` or
+/// `return [x, y]`, `None` for any other value.
+fn returned_vars(ret: Node, code: &str) -> Option<IndexSet<String>> {
+    let mut cursor = ret.walk();
+    let value = ret
+        .named_children(&mut cursor)
+        .find(|n| n.kind() != "comment");
+    let value = match value {
+        Some(value) => value,
+        None => return Some(Default::default()),
+    };
+    let items = match value.kind() {
+        "identifier" => vec![value],
+        "list" | "tuple" | "expression_list" | "parenthesized_expression" => {
+            let mut cursor = value.walk();
+            value
+                .named_children(&mut cursor)
+                .filter(|n| n.kind() != "comment")
+                .collect()
+        }
+        _ => return None,
+    };
+    items
+        .into_iter()
+        .map(|n| match n.kind() {
+            "identifier" => Some(code[n.byte_range()].to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Every `return` of a function body, not counting nested functions.
+fn returns_in(block: Node) -> Vec<Node> {
+    let mut returns = vec![];
+    let mut stack = vec![block];
+    while let Some(node) = stack.pop() {
+        match node.kind() {
+            "return_statement" => returns.push(node),
+            "function_definition" | "class_definition" | "lambda" => {}
+            _ => {
+                let mut cursor = node.walk();
+                stack.extend(node.named_children(&mut cursor));
+            }
+        }
+    }
+    returns.sort_by_key(|r| r.start_byte());
+    returns
+}
+
+/// The `return` ending a function body, if its last statement is one.
+fn final_return(block: Node) -> Option<Node> {
+    let mut cursor = block.walk();
+    let last = block
+        .named_children(&mut cursor)
+        .filter(|n| n.kind() != "comment")
+        .last()?;
+    if last.kind() == "return_statement" {
+        Some(last)
+    } else {
+        None
+    }
+}
+
+/// The statements of `block` before byte `end` the node query of `pack`
+/// matches, with the node each calls. Calls in nested blocks don't count,
+/// and neither do calls with other arguments than variables, like `helper(1)`.
+fn node_calls<'t>(
+    pack: &QueryPack,
+    block: Node<'t>,
+    end: usize,
+    code: &TangleString,
+) -> Result<Vec<(Node<'t>, String)>, Error> {
+    let mut calls: Vec<(Node, String)> = vec![];
+    for qm in tree_sitter::QueryCursor::new().matches(&pack.node, block, code.clone()) {
+        let name = pack.node_capture(&qm, pack.node_name)?;
+        let args = pack.node_capture(&qm, pack.node_param)?;
+        let mut stmt = name;
+        while let Some(parent) = stmt.parent().filter(|p| *p != block) {
+            stmt = parent;
+        }
+        let nested = std::iter::successors(name.parent(), |n| n.parent())
+            .take_while(|n| *n != block)
+            .any(|n| n.kind() == "block");
+        let mut cursor = args.walk();
+        let variables = args.kind() != "argument_list"
+            || args
+                .named_children(&mut cursor)
+                .all(|a| matches!(a.kind(), "identifier" | "keyword_argument" | "comment"));
+        if nested
+            || !variables
+            || stmt.start_byte() >= end
+            || calls.iter().any(|(call, _)| *call == stmt)
+        {
+            continue;
+        }
+        calls.push((stmt, code.0[name.byte_range()].to_string()));
+    }
+    calls.sort_by_key(|(stmt, _)| stmt.start_byte());
+    Ok(calls)
+}
+
+impl Trivia {
+    /// Module code, id marker and decorators above the signature `def`.
+    fn head(&self, id: Option<&String>, def: String) -> String {
+        let mut lines = vec![];
+        if !self.before.is_empty() {
+            lines.push(self.before.clone());
+        }
+        if let Some(id) = id {
+            lines.push(format!("{} {}", ID_MARKER, id));
+        }
+        lines.extend(self.decorators.iter().cloned());
+        lines.push(def);
+        lines.join("\r\n")
+    }
+
+    /// The rest of the `return` line and everything below it.
+    fn tail(&self) -> String {
+        let mut after_return = self.after_return.splitn(2, "\r\n");
+        let mut tail = after_return.next().unwrap_or("").to_string();
+        if let Some(below) = after_return.next() {
+            tail += "\r\n";
+            tail += &indent(below);
+        }
+        tail + &self.after
+    }
+}
+
 fn singleton(leaf: &Tangle) -> Tangle {
     match leaf.clone() {
         Tangle::Leaf {
             name,
             requires,
             provides,
+            annotations,
             ..
         } => Tangle::Dataflow {
             name: name.clone(),
             requires,
             provides,
+            annotations,
             nodes: IndexMap::from_iter(vec![(name.clone(), leaf.clone())].into_iter()),
+            id: fresh_id(),
+            trivia: Default::default(),
         },
         flow => flow,
     }
 }
 
-// fn merge(d1: &Tangle, d2: &Tangle) -> Tangle {
-//     match (d1, d2) {
-//         (Tangle::Leaf { .. }, _) => merge(&singleton(d1), d2),
-//         (_, Tangle::Leaf { .. }) => merge(d1, &singleton(d2)),
-//         (Tangle::Dataflow { requires: req1, provides: prov1, nodes: n1, .. },
-//          Tangle::Dataflow { requires: req2, provides: prov2, nodes: n2, .. }) => {
-//             match (req1.intersection(prov2).next(), req2.intersection(prov1).next()) {
-//                 (Some(_), Some(_)) => panic!("circular"),
-//                 (None, Some(_)) => Tangle::Dataflow {
-//                     name: d1.name() + d2.name(),
-//                     requires: req1.union(req2.difference(prov1).into()),
-//                     provides: prov1.union(prov2),
-//                     nodes: n1.extend(iter)
-//                 }
-//                 (Some(_), None) =>
-//                 (None, None) =>
-//             }
-//         }
-
-//     }
-// }
-
 fn dedent(s: String, by: usize) -> String {
     let template = " ".repeat(by);
     match s.lines().next() {
@@ -224,26 +1151,301 @@ fn remove_return(s: String) -> String {
     }
 }
 
-pub fn topo_sort(flows: Vec<&Tangle>) -> Vec<Vec<String>> {
-    let mut ts = topological_sort::TopologicalSort::<&String>::new();
+/// Nodes that (transitively) require each other, with the variables passed
+/// around the loop.
+#[derive(Debug, Clone, Default)]
+pub struct Cycle {
+    pub nodes: IndexSet<String>,
+    pub vars: IndexSet<String>,
+}
+
+/// `(provider, consumer, variable)` for every variable a node requires from
+/// another node.
+pub fn dependency_edges<'a>(flows: &[&'a Tangle]) -> Vec<(&'a String, &'a String, &'a String)> {
     let mut inverse_provides = IndexMap::<&String, &String>::new();
-    for node in &flows {
+    for node in flows {
         let (_, provides) = node.deps();
         for p in provides {
             inverse_provides.insert(p, node.name());
         }
     }
-    let mut ordering = vec![vec![]];
-    let mut unseen: IndexSet<&String> = Default::default();
-    for node in &flows {
-        let (requires, provides) = node.deps();
-        unseen.insert(node.name());
+    let mut edges = vec![];
+    for node in flows {
+        let (requires, _) = node.deps();
         for r in requires {
             if let Some(provider) = inverse_provides.get(r) {
-                ts.add_dependency(*provider, node.name())
+                // a node reassigning a variable it requires is not a cycle
+                if *provider != node.name() {
+                    edges.push((*provider, node.name(), r));
+                }
             }
         }
     }
+    edges
+}
+
+pub fn find_cycles(flows: Vec<&Tangle>) -> Vec<Cycle> {
+    let edges = dependency_edges(&flows);
+    let reachable = |from: &String| {
+        let mut seen: IndexSet<&String> = Default::default();
+        let mut frontier = vec![from];
+        while let Some(node) = frontier.pop() {
+            for (_, to, _) in edges.iter().filter(|(p, _, _)| *p == node) {
+                if seen.insert(*to) {
+                    frontier.push(*to);
+                }
+            }
+        }
+        seen
+    };
+    let mut cycles: Vec<Cycle> = vec![];
+    for node in &flows {
+        let name = node.name();
+        if cycles.iter().any(|c| c.nodes.contains(name)) {
+            continue;
+        }
+        let downstream = reachable(name);
+        if !downstream.contains(name) {
+            continue;
+        }
+        let nodes: IndexSet<String> = flows
+            .iter()
+            .map(|n| n.name())
+            .filter(|n| downstream.contains(n) && reachable(n).contains(name))
+            .cloned()
+            .collect();
+        let vars = edges
+            .iter()
+            .filter(|(p, c, _)| nodes.contains(*p) && nodes.contains(*c))
+            .map(|(_, _, v)| (*v).clone())
+            .collect();
+        cycles.push(Cycle { nodes, vars });
+    }
+    cycles
+}
+
+/// Variables provided by more than one node, with the nodes providing them.
+pub fn find_conflicts(flows: Vec<&Tangle>) -> IndexMap<String, IndexSet<String>> {
+    let mut providers: IndexMap<String, IndexSet<String>> = Default::default();
+    for node in flows {
+        let (_, provides) = node.deps();
+        for p in provides {
+            providers
+                .entry(p.clone())
+                .or_default()
+                .insert(node.name().clone());
+        }
+    }
+    providers.retain(|_, nodes| nodes.len() > 1);
+    providers
+}
+
+/// `ErrorKind::TypeMismatch` for the edges whose two ends are annotated with
+/// different types. Python doesn't check annotations, so callers may treat
+/// these as warnings.
+pub fn find_type_mismatches(flows: Vec<&Tangle>) -> Vec<Error> {
+    let by_name: IndexMap<&String, &&Tangle> = flows.iter().map(|n| (n.name(), n)).collect();
+    let normalize = |ty: &String| ty.split_whitespace().collect::<String>();
+    dependency_edges(&flows)
+        .into_iter()
+        .filter_map(|(provider, consumer, var)| {
+            let provided = by_name[provider].annotation(var)?;
+            let required = by_name[consumer].annotation(var)?;
+            if normalize(provided) == normalize(required) {
+                None
+            } else {
+                Some(
+                    ErrorKind::TypeMismatch {
+                        var: var.clone(),
+                        provider: provider.clone(),
+                        provided: provided.clone(),
+                        consumer: consumer.clone(),
+                        required: required.clone(),
+                        span: span_of(&flows, consumer),
+                    }
+                    .into(),
+                )
+            }
+        })
+        .collect()
+}
+
+/// A node changing a variable of another node in place, like `xs.append(x)`
+/// or `df["c"] = ..`, which neither the graph nor memoization can see.
+#[derive(Debug, Clone)]
+pub struct Mutation {
+    pub var: String,
+    pub provider: String,
+    pub consumer: String,
+    /// where in the consumer's code the change happens
+    pub span: Span,
+    /// see `scope::Change`
+    pub guessed: bool,
+}
+
+impl std::fmt::Display for Mutation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` {} `{}` of `{}` in place on line {}, nodes reading it may see stale data",
+            self.consumer,
+            if self.guessed {
+                "probably changes"
+            } else {
+                "changes"
+            },
+            self.var,
+            self.provider,
+            self.span.start.row + 1
+        )
+    }
+}
+
+/// Edges whose consumer changes the variable in place.
+pub fn find_mutations(flows: Vec<&Tangle>) -> Vec<Mutation> {
+    let mutations: IndexMap<&String, IndexMap<String, scope::Change>> =
+        flows.iter().map(|n| (n.name(), n.mutations())).collect();
+    dependency_edges(&flows)
+        .into_iter()
+        .filter_map(|(provider, consumer, var)| {
+            let change = mutations[consumer].get(var)?;
+            Some(Mutation {
+                var: var.clone(),
+                provider: provider.clone(),
+                consumer: consumer.clone(),
+                span: change.span.clone(),
+                guessed: change.guessed,
+            })
+        })
+        .collect()
+}
+
+/// Every node that (transitively) requires something `node` provides.
+pub fn downstream(flows: Vec<&Tangle>, node: &str) -> IndexSet<String> {
+    let edges = dependency_edges(&flows);
+    let mut seen: IndexSet<String> = Default::default();
+    let mut frontier = vec![node.to_string()];
+    while let Some(node) = frontier.pop() {
+        for (_, to, _) in edges.iter().filter(|(p, _, _)| **p == node) {
+            if seen.insert((*to).clone()) {
+                frontier.push((*to).clone());
+            }
+        }
+    }
+    seen
+}
+
+/// Rejects graphs where a variable has several providers, as which one a
+/// consumer would see depends on the order the nodes happen to be in.
+pub fn topo_sort(flows: Vec<&Tangle>) -> Result<Vec<Vec<String>>, Error> {
+    if let Some((var, nodes)) = find_conflicts(flows.clone()).into_iter().next() {
+        let span = span_of(&flows, &nodes[1]);
+        return Err(ErrorKind::ConflictingProviders { var, nodes, span }.into());
+    }
+    let cycles = find_cycles(flows.clone());
+    if !cycles.is_empty() {
+        let span = span_of(&flows, &cycles[0].nodes[0]);
+        return Err(ErrorKind::Cycle { cycles, span }.into());
+    }
+    if let Some((param, nodes)) = find_default_conflicts(&flows) {
+        let span = span_of(&flows, &nodes[0]);
+        return Err(ErrorKind::ConflictingDefaults { param, nodes, span }.into());
+    }
+    let after = mutation_order(&flows);
+    Ok(layout(flows, &after))
+}
+
+/// Runs the other readers of a variable a node changes in place after that
+/// node, as if it provided a new version of the variable. Readers the change
+/// itself waits for stay before it.
+fn mutation_order(flows: &[&Tangle]) -> Vec<(String, String)> {
+    let mut edges: Vec<(String, String)> = dependency_edges(flows)
+        .into_iter()
+        .map(|(provider, consumer, _)| (provider.clone(), consumer.clone()))
+        .collect();
+    let reaches = |edges: &[(String, String)], from: &String, to: &String| {
+        let mut seen: IndexSet<&String> = Default::default();
+        let mut frontier = vec![from];
+        while let Some(node) = frontier.pop() {
+            if node == to {
+                return true;
+            }
+            for (_, next) in edges.iter().filter(|(p, _)| p == node) {
+                if seen.insert(next) {
+                    frontier.push(next);
+                }
+            }
+        }
+        false
+    };
+    let mut after = vec![];
+    for mutation in find_mutations(flows.to_vec()) {
+        for (provider, reader, var) in dependency_edges(flows) {
+            if provider != &mutation.provider
+                || var != &mutation.var
+                || reader == &mutation.consumer
+                || reaches(&edges, reader, &mutation.consumer)
+            {
+                continue;
+            }
+            let edge = (mutation.consumer.clone(), reader.clone());
+            edges.push(edge.clone());
+            after.push(edge);
+        }
+    }
+    after
+}
+
+/// A flow parameter that passes the defaults of several nodes, like that of
+/// leaf `a` with `b_c` and leaf `a_b` with `c`, or that is also a variable.
+fn find_default_conflicts(flows: &[&Tangle]) -> Option<(String, IndexSet<String>)> {
+    let vars: IndexSet<&String> = flows
+        .iter()
+        .flat_map(|node| {
+            let (requires, provides) = node.deps();
+            requires.iter().chain(provides)
+        })
+        .collect();
+    let mut passing: IndexMap<String, IndexSet<String>> = Default::default();
+    for node in flows {
+        for (param, _) in node.flow_defaults() {
+            passing
+                .entry(param)
+                .or_default()
+                .insert(node.name().clone());
+        }
+    }
+    passing
+        .into_iter()
+        .find(|(param, nodes)| nodes.len() > 1 || vars.contains(param))
+}
+
+/// Where the node `name` is defined, if it was parsed from code.
+fn span_of(flows: &[&Tangle], name: &str) -> Option<Span> {
+    flows
+        .iter()
+        .find(|node| node.name() == name)?
+        .trivia()
+        .span
+        .clone()
+}
+
+/// Like `topo_sort`, but nodes stuck on a cycle are put in a last level
+/// instead of being reported, for laying out graphs that are being edited.
+pub fn topo_layout(flows: Vec<&Tangle>) -> Vec<Vec<String>> {
+    layout(flows, &[])
+}
+
+fn layout(flows: Vec<&Tangle>, after: &[(String, String)]) -> Vec<Vec<String>> {
+    let mut ts = topological_sort::TopologicalSort::<&String>::new();
+    let mut ordering = vec![vec![]];
+    let mut unseen: IndexSet<&String> = flows.iter().map(|node| node.name()).collect();
+    for (provider, consumer, _) in dependency_edges(&flows) {
+        ts.add_dependency(provider, consumer)
+    }
+    for (before, node) in after {
+        ts.add_dependency(before, node)
+    }
     let ori_ordering = flows
         .iter()
         .map(|t| t.name())
@@ -251,9 +1453,12 @@ pub fn topo_sort(flows: Vec<&Tangle>) -> Vec<Vec<String>> {
         .map(|(i, n)| (n, i))
         .collect::<IndexMap<&String, usize>>();
 
-    let seen: IndexSet<&String> = Default::default();
     while !ts.is_empty() {
         let mut level: Vec<String> = ts.pop_all().into_iter().map(|s| s.clone()).collect();
+        if level.is_empty() {
+            // only cycles are left
+            break;
+        }
         level.sort_by_key(|f| ori_ordering[f]);
         for node in level.iter() {
             unseen.remove(node);
@@ -289,8 +1494,8 @@ pub fn identifier_occurs_before(
 }
 
 impl Tangle {
-    pub fn from_leaves(name: String, nodes: IndexMap<String, Tangle>) -> Tangle {
-        let ordering = topo_sort(nodes.values().collect());
+    pub fn from_leaves(name: String, nodes: IndexMap<String, Tangle>) -> Result<Tangle, Error> {
+        let ordering = topo_sort(nodes.values().collect())?;
         let all_requires: IndexSet<String> = ordering
             .iter()
             .flatten()
@@ -303,41 +1508,468 @@ impl Tangle {
             .map(|n| nodes[n].deps().1.clone().into_iter())
             .flatten()
             .collect();
-        let requires = all_requires
+        let requires: IndexSet<String> = all_requires
             .difference(&provides)
             .map(|s| s.clone())
             .collect();
+        // the provider's annotation, or the one all consumers agree on
+        let annotations = requires
+            .iter()
+            .chain(provides.iter())
+            .filter_map(|v| {
+                let provider = nodes.values().find(|n| n.deps().1.contains(v));
+                if let Some(ty) = provider.and_then(|n| n.annotation(v)) {
+                    return Some((v.clone(), ty.clone()));
+                }
+                let mut types = nodes
+                    .values()
+                    .filter(|n| n.deps().0.contains(v))
+                    .filter_map(|n| n.annotation(v));
+                let ty = types.next()?;
+                if types.all(|other| other == ty) {
+                    Some((v.clone(), ty.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect();
 
-        Tangle::Dataflow {
+        Ok(Tangle::Dataflow {
+            id: id_for_name(&name),
             name,
             requires,
             provides,
             nodes,
-        }
+            annotations,
+            trivia: Default::default(),
+        })
     }
 
-    pub fn emit_all(&self) -> String {
-        self.emit_decorated(&None)
+    /// The smallest flow computing `provides`, sub-flows are sliced down to
+    /// the variables the rest of the slice needs from them.
+    pub fn slice_for(&self, provides: &[&str]) -> Result<Tangle, Error> {
+        let mut inverse_provides = IndexMap::<&String, &String>::new();
+        let nodes = match self {
+            Tangle::Leaf { .. } => vec![self],
+            Tangle::Dataflow { nodes, .. } => nodes.values().collect(),
+        };
+        for node in nodes {
+            for p in node.deps().1 {
+                inverse_provides.insert(p, node.name());
+            }
+        }
+        if let Some(var) = provides
+            .iter()
+            .find(|v| !inverse_provides.contains_key(&v.to_string()))
+        {
+            return Err(ErrorKind::UnknownVariable {
+                var: var.to_string(),
+                span: self.trivia().span.clone(),
+            }
+            .into());
+        }
+        let (name, nodes) = match self {
+            Tangle::Leaf { .. } => return Ok(self.clone()),
+            Tangle::Dataflow { name, nodes, .. } => (name, nodes),
+        };
+
+        let mut needed: IndexMap<String, IndexSet<String>> = Default::default();
+        let mut frontier: Vec<String> = provides.iter().map(|v| v.to_string()).collect();
+        while let Some(var) = frontier.pop() {
+            // anything not provided here is required from outside the slice
+            if let Some(provider) = inverse_provides.get(&var) {
+                if !needed.entry((*provider).clone()).or_default().insert(var) {
+                    continue;
+                }
+                let node = &nodes[*provider];
+                let requires = match node {
+                    Tangle::Leaf { requires, .. } => requires.clone(),
+                    Tangle::Dataflow { .. } => {
+                        let vars = needed[*provider]
+                            .iter()
+                            .map(|v| v.as_str())
+                            .collect::<Vec<_>>();
+                        node.slice_for(&vars)?.deps().0.clone()
+                    }
+                };
+                frontier.extend(requires);
+            }
+        }
+
+        let mut sliced: IndexMap<String, Tangle> = Default::default();
+        for (k, node) in nodes.iter().filter(|(k, _)| needed.contains_key(*k)) {
+            let node = match node {
+                Tangle::Leaf { .. } => node.clone(),
+                Tangle::Dataflow { .. } => {
+                    node.slice_for(&needed[k].iter().map(|v| v.as_str()).collect::<Vec<_>>())?
+                }
+            };
+            sliced.insert(k.clone(), node);
+        }
+        let mut flow = Tangle::from_leaves(name.clone(), sliced)?;
+        if let Tangle::Dataflow { provides: p, .. } = &mut flow {
+            *p = provides.iter().map(|v| v.to_string()).collect();
+        }
+        flow.keep_trivia(self);
+        flow.set_id(self.id().clone());
+        Ok(flow)
     }
-    pub fn emit_decorated(&self, decorate: &Option<&str>) -> String {
+
+    /// `find_type_mismatches` of this flow and its sub-flows.
+    pub fn type_mismatches(&self) -> Vec<Error> {
         match self {
-            Tangle::Leaf { .. } => self.emit(),
+            Tangle::Leaf { .. } => vec![],
+            Tangle::Dataflow { nodes, .. } => find_type_mismatches(nodes.values().collect())
+                .into_iter()
+                .chain(nodes.values().flat_map(|node| node.type_mismatches()))
+                .collect(),
+        }
+    }
+
+    /// Like `type_mismatches`, for when a mismatch should stop an export.
+    pub fn check_types(&self) -> Result<(), Error> {
+        match self.type_mismatches().into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Names of the leaves of this flow, nested ones included, that have to
+    /// rerun when the leaf `node` changes, `node` itself excluded.
+    pub fn impact_of(&self, node: &str) -> IndexSet<String> {
+        let mut rerun = self.rerun(node, &Default::default()).0;
+        rerun.shift_remove(node);
+        rerun
+    }
+
+    /// The leaves that rerun when the leaf `node` or the required variables
+    /// `changed` change, and the provided variables that change with them.
+    fn rerun(
+        &self,
+        node: &str,
+        changed: &IndexSet<String>,
+    ) -> (IndexSet<String>, IndexSet<String>) {
+        let (requires, provides) = self.deps();
+        let nodes = match self {
+            Tangle::Leaf { name, .. } if name == node || !requires.is_disjoint(changed) => {
+                return (IndexSet::from_iter([name.clone()]), provides.clone());
+            }
+            Tangle::Leaf { .. } => return Default::default(),
+            Tangle::Dataflow { nodes, .. } => nodes,
+        };
+        let mut rerun = IndexSet::new();
+        let mut dirty = changed.clone();
+        for k in topo_layout(nodes.values().collect()).iter().flatten() {
+            let inputs = nodes[k].deps().0.intersection(&dirty).cloned().collect();
+            let (leaves, outputs) = nodes[k].rerun(node, &inputs);
+            rerun.extend(leaves);
+            dirty.extend(outputs);
+        }
+        let outputs = provides.intersection(&dirty).cloned().collect();
+        (rerun, outputs)
+    }
+
+    /// Combines two flows into one, running whichever flow provides the
+    /// other's requirements first.
+    pub fn merge(d1: &Tangle, d2: &Tangle) -> Result<Tangle, Error> {
+        match (d1, d2) {
+            (Tangle::Leaf { .. }, _) => Tangle::merge(&singleton(d1), d2),
+            (_, Tangle::Leaf { .. }) => Tangle::merge(d1, &singleton(d2)),
+            (
+                Tangle::Dataflow {
+                    requires: req1,
+                    provides: prov1,
+                    nodes: n1,
+                    ..
+                },
+                Tangle::Dataflow {
+                    requires: req2,
+                    provides: prov2,
+                    nodes: n2,
+                    ..
+                },
+            ) => {
+                let forward: IndexSet<String> = req2.intersection(prov1).cloned().collect();
+                let backward: IndexSet<String> = req1.intersection(prov2).cloned().collect();
+                // the nodes of `d2` that take part, where the error is shown
+                let span_in_d2 = |involved: &dyn Fn(&Tangle) -> bool| {
+                    n2.values()
+                        .find(|node| involved(node))
+                        .and_then(|node| node.trivia().span.clone())
+                };
+                if !forward.is_empty() && !backward.is_empty() {
+                    let span = span_in_d2(&|node| node.deps().0.contains(&forward[0]));
+                    return Err(ErrorKind::CircularMerge {
+                        forward,
+                        backward,
+                        span,
+                    }
+                    .into());
+                }
+                let provides: IndexSet<String> = prov1.intersection(prov2).cloned().collect();
+                let nodes: IndexSet<String> =
+                    n1.keys().filter(|k| n2.contains_key(*k)).cloned().collect();
+                if !provides.is_empty() || !nodes.is_empty() {
+                    let span = span_in_d2(&|node| {
+                        nodes.contains(node.name()) || !node.deps().1.is_disjoint(&provides)
+                    });
+                    return Err(ErrorKind::ConflictingMerge {
+                        provides,
+                        nodes,
+                        span,
+                    }
+                    .into());
+                }
+
+                let ((req_up, prov_up, n_up), (req_down, prov_down, n_down)) =
+                    if backward.is_empty() {
+                        ((req1, prov1, n1), (req2, prov2, n2))
+                    } else {
+                        ((req2, prov2, n2), (req1, prov1, n1))
+                    };
+                Ok(Tangle::Dataflow {
+                    name: format!("{}_{}", d1.name(), d2.name()),
+                    requires: req_up
+                        .iter()
+                        .chain(req_down.difference(prov_up))
+                        .cloned()
+                        .collect(),
+                    provides: prov_up.union(prov_down).cloned().collect(),
+                    nodes: n_up
+                        .iter()
+                        .chain(n_down.iter())
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect(),
+                    annotations: d1
+                        .annotations()
+                        .iter()
+                        .chain(d2.annotations().iter())
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect(),
+                    id: fresh_id(),
+                    trivia: Default::default(),
+                })
+            }
+        }
+    }
+
+    /// The source of every function of the flow, which `from_code` reads
+    /// back, leaf ids included.
+    pub fn emit_all(&self) -> Result<String, Error> {
+        self.emit_functions(&None, false, true)
+    }
+
+    /// `emit_all` to run, without ids and with `decorate` on every leaf.
+    pub fn emit_decorated(&self, decorate: &Option<&str>) -> Result<String, Error> {
+        self.emit_functions(decorate, false, decorate.is_none())
+    }
+
+    /// Like `emit_all`, but flows are coroutines that run the nodes of each
+    /// level of `topo_sort` concurrently, see `emit_async_flow`.
+    pub fn emit_async(&self) -> Result<String, Error> {
+        let functions = self.emit_functions(&None, true, false)?;
+        Ok(format!(
+            "import asyncio\r\nimport functools\r\n\r\n{}",
+            functions
+        ))
+    }
+
+    fn emit_functions(
+        &self,
+        decorate: &Option<&str>,
+        asynchronous: bool,
+        ids: bool,
+    ) -> Result<String, Error> {
+        let mut emitted = IndexMap::new();
+        self.emit_nested(decorate, asynchronous, ids, &mut emitted)?;
+        // functions read from a file keep their order in it, so does the module
+        // code between them, new ones stay above the function emitted after them
+        let mut at = usize::MAX;
+        let mut defs: Vec<(usize, String)> = emitted
+            .into_iter()
+            .rev()
+            .map(|(_, (start, def))| {
+                at = start.unwrap_or(at);
+                (at, def)
+            })
+            .collect();
+        defs.reverse();
+        defs.sort_by_key(|(at, _)| *at);
+        Ok(defs
+            .into_iter()
+            .map(|(_, def)| def)
+            .collect::<Vec<String>>()
+            .join("\r\n\r\n"))
+    }
+
+    /// Emits a script that runs without tangle: the undecorated functions of
+    /// `emit_all`, or `emit_async` if `asynchronous`, then a `__main__` entry
+    /// taking the requirements and defaults as command line options and
+    /// printing or pickling the provides.
+    pub fn emit_script(&self, asynchronous: bool) -> Result<String, Error> {
+        let (requires, provides) = self.deps();
+        let annotations = self.annotations();
+        let functions = if asynchronous {
+            self.emit_async()?
+        } else {
+            self.emit_functions(&None, false, false)?
+        };
+        let mut lines = vec![functions, String::new()];
+        if requires.contains("gui") {
+            lines.extend(HEADLESS_GUI.lines().map(|l| l.to_string()));
+        }
+        lines.extend(SCRIPT_VALUE.lines().map(|l| l.to_string()));
+        lines.push(String::new());
+        lines.push("if __name__ == \"__main__\":".to_string());
+        lines.push("    import argparse".to_string());
+        lines.push(String::new());
+        lines.push(format!(
+            "    parser = argparse.ArgumentParser(description=\"Runs `{}`.\")",
+            self.name()
+        ));
+        let kind = |ty: Option<&String>| match ty.map(|ty| ty.as_str()) {
+            Some(ty @ "int") | Some(ty @ "float") | Some(ty @ "str") => ty.to_string(),
+            _ => "_value".to_string(),
+        };
+        let defaults = self.defaults();
+        let default_types = self.default_annotations();
+        let mut args = vec![];
+        for var in requires.iter().filter(|var| *var != "gui") {
+            lines.push(format!(
+                "    parser.add_argument(\"--{}\", type={}, required=True)",
+                var,
+                kind(annotations.get(var))
+            ));
+            args.push(format!("{}=args.{}", var, var));
+        }
+        for (param, value) in &defaults {
+            lines.push(format!(
+                "    parser.add_argument(\"--{}\", type={}, default={})",
+                param,
+                kind(default_types.get(param)),
+                value
+            ));
+            args.push(format!("{}=args.{}", param, param));
+        }
+        if requires.contains("gui") {
+            args.push("gui=_HeadlessGui()".to_string());
+        }
+        // `--out` unless a variable has the name
+        let out = if requires.contains("out") || defaults.contains_key("out") {
+            "_out"
+        } else {
+            "out"
+        };
+        lines.push(format!(
+            "    parser.add_argument(\"--{}\", dest=\"_out\", help=\"pickle the results to this file\")",
+            out
+        ));
+        lines.push("    args = parser.parse_args()".to_string());
+        let call = format!("{}({})", self.name(), args.join(", "));
+        lines.push(if asynchronous && self.is_coroutine() {
+            format!("    results = asyncio.run({})", call)
+        } else {
+            format!("    results = {}", call)
+        });
+        lines.push(format!(
+            "    results = dict(zip([{}], results))",
+            provides
+                .iter()
+                .map(|p| format!("\"{}\"", p))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+        lines.extend(SCRIPT_OUTPUT.lines().map(|l| l.to_string()));
+        Ok(lines.join("\r\n") + "\r\n")
+    }
+
+    /// Emits every function `self` depends on before `self`, skipping
+    /// leaves and sub-flows that are shared between several flows.
+    fn emit_nested(
+        &self,
+        decorate: &Option<&str>,
+        asynchronous: bool,
+        ids: bool,
+        emitted: &mut IndexMap<String, (Option<usize>, String)>,
+    ) -> Result<(), Error> {
+        if emitted.contains_key(self.name()) {
+            return Ok(());
+        }
+        let def = match self {
+            Tangle::Leaf { name, .. } => {
+                if let Some(decorator) = decorate {
+                    let mut leaf = self.clone();
+                    if let Tangle::Leaf { trivia, .. } = &mut leaf {
+                        let memo = format!("{}(\"{}\")", decorator, name);
+                        trivia.decorators.insert(0, memo);
+                    }
+                    leaf.emit_with(ids, asynchronous)?
+                } else {
+                    self.emit_with(ids, asynchronous)?
+                }
+            }
             Tangle::Dataflow { nodes, .. } => {
-                let leaves = nodes
-                    .values()
-                    .map(|t| {
-                        if let Some(decorator) = decorate {
-                            format!("{}(\"{}\")\r\n{}", decorator, t.name(), t.emit())
-                        } else {
-                            t.emit()
-                        }
-                    })
-                    .collect::<Vec<String>>()
-                    .join("\r\n\r\n")
-                    + "\r\n\r\n";
-                leaves + self.emit().as_str()
+                for node in nodes.values() {
+                    node.emit_nested(decorate, asynchronous, ids, emitted)?;
+                }
+                if asynchronous {
+                    self.emit_async_flow()?
+                } else {
+                    self.emit_with(ids, false)?
+                }
+            }
+        };
+        let start = self.trivia().span.as_ref().map(|span| span.bytes.start);
+        emitted.insert(self.name().clone(), (start, def));
+        Ok(())
+    }
+
+    /// All leaves of a (possibly nested) flow, each with the names of the
+    /// sub-flows enclosing it.
+    pub fn leaves(&self) -> Vec<(Vec<String>, &Tangle)> {
+        match self {
+            Tangle::Leaf { .. } => vec![(vec![], self)],
+            Tangle::Dataflow { nodes, .. } => nodes
+                .values()
+                .flat_map(|node| match node {
+                    Tangle::Leaf { .. } => vec![(vec![], node)],
+                    Tangle::Dataflow { name, .. } => node
+                        .leaves()
+                        .into_iter()
+                        .map(|(mut path, leaf)| {
+                            path.insert(0, name.clone());
+                            (path, leaf)
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Inverse of `leaves`, nests each leaf in the sub-flows named by its path.
+    pub fn from_paths(name: String, nodes: Vec<(Vec<String>, Tangle)>) -> Result<Tangle, Error> {
+        let mut order: IndexSet<String> = Default::default();
+        let mut direct: IndexMap<String, Tangle> = Default::default();
+        let mut nested: IndexMap<String, Vec<(Vec<String>, Tangle)>> = Default::default();
+        for (mut path, node) in nodes {
+            if path.is_empty() {
+                order.insert(node.name().clone());
+                direct.insert(node.name().clone(), node);
+            } else {
+                let head = path.remove(0);
+                order.insert(head.clone());
+                nested.entry(head).or_default().push((path, node));
             }
         }
+        let mut nodes: IndexMap<String, Tangle> = Default::default();
+        for name in order {
+            let node = match direct.remove(&name) {
+                Some(leaf) => leaf,
+                None => Tangle::from_paths(name.clone(), nested.remove(&name).unwrap())?,
+            };
+            nodes.insert(name, node);
+        }
+        Tangle::from_leaves(name, nodes)
     }
 
     fn get_provides(
@@ -352,16 +1984,16 @@ impl Tangle {
         parser.set_language(tree_sitter_python::language()).unwrap();
         let tree = parser.parse(code, None).unwrap();
         let root = tree.root_node();
-        let captures = CELL_QUERY.capture_names();
+        let pack = QueryPack::active();
+        let captures = pack.cell.capture_names();
         let mut modules: IndexSet<String> = Default::default();
         let mut identifier: IndexMap<String, DocRange> = Default::default();
         let mut node_provides: IndexSet<String> = Default::default();
 
-        for qm in tree_sitter::QueryCursor::new().matches(&CELL_QUERY, tree.root_node(), ts) {
+        for qm in tree_sitter::QueryCursor::new().matches(&pack.cell, tree.root_node(), ts) {
             for cap in qm.captures {
                 match captures[cap.index as usize].as_str() {
-                    "error" => {}
-                    "import" => {
+                    "import" if binds_in_cell(cap.node, false) => {
                         modules.insert(code[cap.node.byte_range()].to_string());
                         node_provides.insert(code[cap.node.byte_range()].to_string());
                     }
@@ -376,9 +2008,24 @@ impl Tangle {
                             }
                         }
                     }
-                    _ => {
+                    "variable" => {
+                        let walrus =
+                            cap.node.parent().map(|p| p.kind()) == Some("named_expression");
+                        if binds_in_cell(cap.node, walrus) {
+                            for name in scope::identifiers(cap.node) {
+                                node_provides.insert(code[name.byte_range()].to_string());
+                            }
+                        }
+                    }
+                    "function" | "class"
+                        if cap
+                            .node
+                            .parent()
+                            .is_some_and(|def| binds_in_cell(def, false)) =>
+                    {
                         node_provides.insert(code[cap.node.byte_range()].to_string());
                     }
+                    _ => {}
                 }
             }
         }
@@ -387,30 +2034,23 @@ impl Tangle {
         (node_provides, identifier, modules)
     }
 
-    fn get_requires(cell: &String) -> pyo3::PyResult<IndexSet<String>> {
-        pyo3::Python::with_gil(|py| {
-            let get_globals = py.import("tangle.pyapi")?.getattr("cell_load_globals")?;
-            let builtins = py.import("builtins")?.dict();
-            let cell_requires: Vec<String> = get_globals.call1((cell, builtins))?.extract()?;
-            Ok(IndexSet::from_iter(cell_requires.into_iter()))
-        })
-    }
-
     pub fn from_cell(
         name: String,
         cell: &String,
-    ) -> Result<
-        (Tangle, IndexMap<String, DocRange>),
-        (pyo3::PyErr, (Tangle, IndexMap<String, DocRange>)),
-    > {
+    ) -> Result<(Tangle, IndexMap<String, DocRange>), (Error, (Tangle, IndexMap<String, DocRange>))>
+    {
         let (provides, identifier, modules) = Tangle::get_provides(cell);
-        match Tangle::get_requires(cell) {
+        match scope::analyze(cell).map(|analysis| analysis.requires) {
             Ok(requires) => Ok((
                 Tangle::Leaf {
                     code: cell.to_string(),
                     provides,
                     requires,
                     name,
+                    annotations: Default::default(),
+                    id: fresh_id(),
+                    defaults: Default::default(),
+                    trivia: Default::default(),
                 },
                 identifier,
             )),
@@ -422,6 +2062,10 @@ impl Tangle {
                         provides,
                         requires: Default::default(),
                         name,
+                        annotations: Default::default(),
+                        id: fresh_id(),
+                        defaults: Default::default(),
+                        trivia: Default::default(),
                     },
                     identifier,
                 ),
@@ -429,104 +2073,389 @@ impl Tangle {
         }
     }
 
-    fn flow_from_match(
-        qm: &QueryMatch,
+    /// Splits a flat script into leaves `{name}_1`, `{name}_2`, .. of a new
+    /// flow. Consecutive imports form one leaf, a statement that binds a name
+    /// after a blank line starts a new leaf, and other statements stay with the
+    /// leaf before them. A statement rebinding a name of an earlier leaf pulls
+    /// everything since that leaf into one, so each name has one provider.
+    pub fn split_script(name: String, code: &String) -> Result<Tangle, Error> {
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let tree = parser.parse(code, None).unwrap();
+        let root = tree.root_node();
+
+        // (bytes, provides, only imports) of each group
+        let mut groups: Vec<(std::ops::Range<usize>, IndexSet<String>, bool)> = vec![];
+        let mut start = None;
+        let mut cursor = root.walk();
+        for stmt in root.named_children(&mut cursor) {
+            if stmt.kind() == "comment" {
+                // comments belong to the statement after them
+                start.get_or_insert(stmt.start_byte());
+                continue;
+            }
+            let bytes = start.take().unwrap_or(stmt.start_byte())..stmt.end_byte();
+            let (provides, _, _) = Tangle::get_provides(&code[stmt.byte_range()].to_string());
+            let import = matches!(stmt.kind(), "import_statement" | "import_from_statement");
+            let rebinds = groups
+                .iter()
+                .position(|(_, bound, _)| !bound.is_disjoint(&provides));
+            let joins = match (rebinds, groups.last()) {
+                (Some(_), _) => true,
+                (None, None) => false,
+                (None, Some((_, _, imports))) if import => *imports,
+                (None, Some((group, bound, imports))) => {
+                    let paragraph = code[group.end..bytes.start].matches('\n').count() > 1;
+                    provides.is_empty() || !imports && (bound.is_empty() || !paragraph)
+                }
+            };
+            if let Some(first) = rebinds {
+                let merged = groups.drain(first..).reduce(|mut a, b| {
+                    a.0.end = b.0.end;
+                    a.1.extend(b.1);
+                    a.2 &= b.2;
+                    a
+                });
+                groups.extend(merged);
+            }
+            match groups.last_mut() {
+                Some(group) if joins => {
+                    group.0.end = bytes.end;
+                    group.1.extend(provides);
+                    group.2 &= import;
+                }
+                _ => groups.push((bytes, provides, import)),
+            }
+        }
+        // trailing comments stay with the last leaf, or are one on their own
+        match (start, groups.last_mut()) {
+            (Some(_), Some(group)) => group.0.end = code.len(),
+            (Some(start), None) => groups.push((start..code.len(), Default::default(), false)),
+            (None, _) => {}
+        }
+
+        let mut nodes: IndexMap<String, Tangle> = Default::default();
+        for (i, (bytes, _, _)) in groups.into_iter().enumerate() {
+            let leaf_name = format!("{}_{}", name, i + 1);
+            let leaf = match Tangle::from_cell(leaf_name.clone(), &code[bytes].to_string()) {
+                Ok((leaf, _)) => leaf,
+                Err((_, (partial_leaf, _))) => partial_leaf,
+            };
+            nodes.insert(leaf_name, leaf);
+        }
+        Tangle::from_leaves(name, nodes)
+    }
+
+    fn flow_from_nodes(
+        pack: &QueryPack,
+        name: &String,
+        flows: &IndexMap<String, Vec<(Node, String)>>,
         leaves: &IndexMap<String, Tangle>,
         code: &String,
+        visiting: &mut IndexSet<String>,
     ) -> Result<Tangle, Error> {
         let ts = TangleString::from(code.as_str());
-        let get_code = |idx| {
-            qm.nodes_for_capture_index(idx)
-                .map(|n| code[n.byte_range()].to_string())
-        };
-        let name = get_code(*LEAF_NAME).into_iter().next().unwrap();
-        let (requires, provides) = leaves[&name].deps();
-        let mut uses_leaves: Vec<String> = vec![];
-        for node in qm.nodes_for_capture_index(*FLOW_NODE) {
-            for (qm, _) in tree_sitter::QueryCursor::new().captures(&NODE_QUERY, node, ts.clone()) {
-                let get_code = |idx| {
-                    qm.nodes_for_capture_index(idx)
-                        .map(|n| code[n.byte_range()].to_string())
-                };
-                let node_name = get_code(*FLOW_NODE_NAME).next().unwrap();
-                let node_requires = parse_id_or_seq(*FLOW_NODE_PARAM, &qm, &ts);
-                let node_provides = parse_id_or_seq(*FLOW_NODE_PROVIDES, &qm, &ts);
+        let (requires, provides) = leaves[name].deps();
+        visiting.insert(name.clone());
+        let mut nodes: IndexMap<String, Tangle> = Default::default();
+        let mut overrides = vec![];
+        for (node, _) in &flows[name] {
+            // the match `node_calls` picked the statement for
+            let mut cursor = tree_sitter::QueryCursor::new();
+            if let Some(qm) = cursor.matches(&pack.node, *node, ts.clone()).next() {
+                let name_node = pack.node_capture(&qm, pack.node_name)?;
+                let node_name = code[name_node.byte_range()].to_string();
+                let node_requires = parse_id_or_seq(pack.node_param, &qm, &ts);
+                let node_provides = parse_id_or_seq(pack.node_provides, &qm, &ts);
+                let span = Span::from(*node);
                 let leaf = leaves
                     .get(&node_name)
-                    .ok_or_else(|| Error::MissingLeaf(node_name.clone()))?;
-                if let Tangle::Leaf {
-                    requires, provides, ..
-                } = &leaf
-                {
-                    if !(requires == &node_requires) {
-                        return Err(Error::RequiresMismatch {
-                            expected: node_requires,
-                            found: requires.clone(),
-                        });
-                    } else if !(provides == &node_provides) {
-                        return Err(Error::ProvidesMismatch {
-                            expected: node_provides,
-                            found: provides.clone(),
-                        });
+                    .ok_or_else(|| ErrorKind::MissingLeaf {
+                        name: node_name.clone(),
+                        span: Span::from(name_node),
+                    })?;
+                let sub = if !flows.contains_key(&node_name) {
+                    leaf.clone()
+                } else if visiting.contains(&node_name) {
+                    return Err(ErrorKind::RecursiveFlow {
+                        name: node_name,
+                        span,
                     }
-                    uses_leaves.push(node_name);
+                    .into());
                 } else {
-                    panic!("Only leaves for now")
+                    Tangle::flow_from_nodes(pack, &node_name, flows, leaves, code, visiting)?
+                };
+                let (requires, provides) = sub.deps();
+                if !(requires == &node_requires) {
+                    return Err(ErrorKind::RequiresMismatch {
+                        expected: node_requires,
+                        found: requires.clone(),
+                        span,
+                    }
+                    .into());
+                } else if !(provides == &node_provides) {
+                    return Err(ErrorKind::ProvidesMismatch {
+                        expected: node_provides,
+                        found: provides.clone(),
+                        span,
+                    }
+                    .into());
+                }
+                // `node(x, scale=3.0)` gives the node its own value of a default
+                for arg in qm.nodes_for_capture_index(pack.node_param) {
+                    let mut cursor = arg.walk();
+                    for kwarg in arg.named_children(&mut cursor) {
+                        let param = kwarg.child_by_field_name("name");
+                        let (param, value) = match (param, kwarg.child_by_field_name("value")) {
+                            (Some(p), Some(v)) if kwarg.kind() == "keyword_argument" => {
+                                (&code[p.byte_range()], &code[v.byte_range()])
+                            }
+                            _ => continue,
+                        };
+                        let passed = match &sub {
+                            Tangle::Leaf { .. } => format!("{}_{}", node_name, param),
+                            Tangle::Dataflow { .. } => param.to_string(),
+                        };
+                        if !sub.defaults().contains_key(param) {
+                            return Err(ErrorKind::UnknownDefault {
+                                node: node_name,
+                                param: param.to_string(),
+                                span: Span::from(kwarg),
+                            }
+                            .into());
+                        }
+                        // the flow parameter passed on, as `emit` writes it
+                        if value != passed {
+                            overrides.push((passed, value.to_string()));
+                        }
+                    }
                 }
+                nodes.insert(node_name, sub);
             }
         }
-        Ok(Tangle::Dataflow {
-            name,
-            nodes: IndexMap::from_iter(
-                uses_leaves
-                    .into_iter()
-                    .map(|n| (n.clone(), leaves.get(&n).map(|n| n.clone()).unwrap())),
-            ),
+        visiting.remove(name);
+        let mut flow = Tangle::Dataflow {
+            name: name.clone(),
+            nodes,
             provides: provides.clone(),
             requires: requires.clone(),
-        })
+            annotations: leaves[name].annotations().clone(),
+            id: leaves[name].id().clone(),
+            trivia: leaves[name].trivia().clone(),
+        };
+        // a default given in the flow signature wins over the leaf's own, and
+        // one given in the call over both
+        for (param, value) in leaves[name].defaults().into_iter().chain(overrides) {
+            flow.set_default(&param, value);
+        }
+        Ok(flow)
+    }
+
+    /// The function of a match with its decorators, or `None` for functions
+    /// nested in other code.
+    fn outer_node<'t>(
+        pack: &QueryPack,
+        qm: &QueryMatch<'_, 't>,
+    ) -> Result<Option<Node<'t>>, Error> {
+        let def = pack.leaf_capture(qm, pack.leaf_def)?;
+        let outer = match def.parent() {
+            Some(p) if p.kind() == "decorated_definition" => p,
+            _ => def,
+        };
+        if outer.parent().map(|p| p.kind()) != Some("module") {
+            return Ok(None);
+        }
+        Ok(Some(outer))
     }
 
-    fn leaf_from_match(qm: &QueryMatch, code: &TangleString) -> Tangle {
-        let get_code = |idx| {
-            qm.nodes_for_capture_index(idx)
+    /// A leaf provides the variables all of its `return`s return. Inner
+    /// returns are rewritten to the canonical `return [x, y]`. `Ok(None)` for
+    /// functions without a `return`. Leaves a pack matches on other code, like
+    /// the body of a dataclass, provide the names their body binds.
+    fn leaf_from_match(
+        pack: &QueryPack,
+        qm: &QueryMatch,
+        code: &TangleString,
+    ) -> Result<Option<Tangle>, Error> {
+        let name = code.0[pack.leaf_capture(qm, pack.leaf_name)?.byte_range()].to_string();
+        let def = pack.leaf_capture(qm, pack.leaf_def)?;
+        let block = pack.leaf_capture(qm, pack.leaf_body)?;
+        // the definition the body belongs to, `@leaf` may be around it
+        let head = block.parent().unwrap_or(def);
+        let function = head.kind() == "function_definition";
+        let outer = match def.parent() {
+            Some(p) if p.kind() == "decorated_definition" => p,
+            _ => def,
+        };
+        let id = outer
+            .prev_named_sibling()
+            .and_then(|marker| {
+                if marker.kind() != "comment"
+                    || marker.end_position().row + 1 != outer.start_position().row
+                {
+                    return None;
+                }
+                let id = code.0[marker.byte_range()].strip_prefix(ID_MARKER)?.trim();
+                Some(id.to_string())
+            })
+            .unwrap_or_else(|| id_for_name(&name));
+        let requires = parse_id_or_seq(pack.leaf_param, qm, code);
+        let returns = returns_in(block);
+        let ret = final_return(block);
+        let mut provides = None;
+        for r in returns.iter().rev() {
+            let vars = returned_vars(*r, code.0).ok_or_else(|| ErrorKind::UnsupportedReturn {
+                name: name.clone(),
+                span: Span::from(*r),
+            })?;
+            match &provides {
+                None => provides = Some(vars),
+                Some(expected) if expected != &vars => {
+                    return Err(ErrorKind::InconsistentReturns {
+                        name: name.clone(),
+                        expected: expected.clone(),
+                        found: vars,
+                        span: Span::from(*r),
+                    }
+                    .into())
+                }
+                Some(_) => {}
+            }
+        }
+        if provides.is_none() && function {
+            return Ok(None);
+        }
+
+        let mut trivia = Trivia {
+            span: Some(Span::from(outer)),
+            ..Default::default()
+        };
+        // emitted on the `def` of the leaf, which a class decorator can't go on
+        if function {
+            let mut cursor = outer.walk();
+            trivia.decorators = outer
+                .named_children(&mut cursor)
+                .filter(|n| n.kind() == "decorator")
                 .map(|n| code.0[n.byte_range()].to_string())
+                .collect();
+        }
+        let indent = returns
+            .first()
+            .map_or(usize::MAX, |r| r.start_position().column)
+            .min(
+                block
+                    .named_child(0)
+                    .map_or(usize::MAX, |n| n.start_position().column),
+            );
+        // the body starts on the line after the `:` of the signature
+        let mut cursor = head.walk();
+        let colon = head
+            .children(&mut cursor)
+            .filter(|n| n.kind() == ":" && n.end_byte() <= block.start_byte())
+            .last()
+            .map_or(head.start_byte(), |n| n.end_byte());
+        let body_start = (line_end(code.0, colon) + 1).min(code.0.len());
+        let body_end = match ret {
+            Some(ret) => line_start(code.0, ret.start_byte()),
+            None => (line_end(code.0, block.end_byte()) + 1).min(code.0.len()),
         };
-        let name = get_code(*LEAF_NAME).into_iter().next();
-        let requires = parse_id_or_seq(*LEAF_PARAM, &qm, &code);
-        let provides = parse_id_or_seq(*LEAF_PROVIDES, &qm, &code);
-        let indent = if let Some(node) = qm.nodes_for_capture_index(*LEAF_BODY).next() {
-            node.start_position().column
-        } else {
-            0
+        let mut body = code.0[body_start.min(body_end)..body_end].to_string();
+        let provides = match provides {
+            Some(provides) => provides,
+            None => match Tangle::get_provides(&dedent(body.clone(), indent)).0 {
+                binds if binds.is_empty() => return Ok(None),
+                binds => binds,
+            },
         };
-        let body = get_code(*LEAF_BODY)
-            .into_iter()
-            .map(|s| dedent(s, indent))
-            .collect::<Vec<_>>()
-            .join("\r\n");
-        Tangle::Leaf {
-            name: name.unwrap(),
+        let annotations = parse_annotations(pack, qm, code, &provides);
+        let defaults = parse_defaults(pack.leaf_param, qm, code);
+        let canonical = format!("return [{}]", join(&provides));
+        for r in returns.iter().rev().filter(|r| Some(**r) != ret) {
+            if r.start_byte() >= body_start && r.end_byte() <= body_end {
+                let range = r.start_byte() - body_start..r.end_byte() - body_start;
+                body.replace_range(range, &canonical);
+            }
+        }
+        let body = lines_between(&body, 0..body.len(), indent);
+        if let Some(ret) = ret {
+            let after_return = &code.0[ret.end_byte()..head.end_byte()];
+            trivia.after_return = match after_return.split_once('\n') {
+                Some((rest, below)) => format!(
+                    "{}\r\n{}",
+                    rest.trim_end_matches('\r'),
+                    dedent(below.to_string(), indent)
+                ),
+                None => after_return.to_string(),
+            };
+        }
+
+        // what a flow keeps around its node calls
+        {
+            let end = ret.map_or(block.end_byte(), |r| r.start_byte());
+            let mut boundary = body_start;
+            for (stmt, node) in node_calls(pack, block, end, code)? {
+                let stmt_line = line_start(code.0, stmt.start_byte());
+                let stmt_end = line_end(code.0, stmt.end_byte());
+                let before = lines_between(code.0, boundary..stmt_line, indent);
+                let inline = &code.0[stmt.end_byte()..stmt_end];
+                if !before.is_empty() {
+                    trivia.node_before.insert(node.clone(), before);
+                }
+                if !inline.trim().is_empty() {
+                    trivia
+                        .node_inline
+                        .insert(node, inline.trim_end_matches('\r').to_string());
+                }
+                boundary = (stmt_end + 1).min(code.0.len());
+            }
+            if ret.is_some() {
+                trivia.before_return = lines_between(code.0, boundary..body_end, indent);
+            }
+        }
+
+        Ok(Some(Tangle::Leaf {
+            name,
             requires,
             provides,
             code: body,
-        }
+            annotations,
+            id,
+            defaults,
+            trivia,
+        }))
+    }
+
+    /// The source of this function alone, see `emit_all`.
+    pub fn emit(&self) -> Result<String, Error> {
+        self.emit_with(true, false)
     }
 
-    pub fn emit(&self) -> String {
+    /// `emit`, with the id marker above the function if `ids`. Leaves that await
+    /// become `async def` if `asynchronous` and are an error otherwise.
+    fn emit_with(&self, ids: bool, asynchronous: bool) -> Result<String, Error> {
         match self {
             Tangle::Leaf {
                 name,
                 provides,
                 requires,
                 code,
+                annotations,
+                id,
+                defaults,
+                trivia,
             } => {
-                let requires = requires
-                    .iter()
-                    .map(|s| s.clone())
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                let head = format!("def {}({}):", name, requires);
+                let mut def = emit_head(name, requires, defaults, provides, annotations);
+                if scope::awaits(code) {
+                    if !asynchronous {
+                        return Err(ErrorKind::SyncAwait {
+                            name: name.clone(),
+                            span: trivia.span.clone(),
+                        }
+                        .into());
+                    }
+                    def = format!("async {}", def);
+                }
+                let head = trivia.head(Some(id).filter(|_| ids), def);
                 let body = code
                     .lines()
                     .map(|l| "    ".to_string() + l)
@@ -537,89 +2466,921 @@ impl Tangle {
                     .map(|s| s.clone())
                     .collect::<Vec<String>>()
                     .join(", ");
-                format!("{}\r\n{}\r\n    return [{}]", head, body, provides)
+                Ok(format!(
+                    "{}\r\n{}\r\n    return [{}]{}",
+                    head,
+                    body,
+                    provides,
+                    trivia.tail()
+                ))
             }
             Tangle::Dataflow {
                 name,
                 provides,
                 requires,
                 nodes,
+                annotations,
+                id,
+                trivia,
             } => {
-                let requires = requires
-                    .iter()
-                    .map(|s| s.clone())
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                let head = format!("def {}({}):", name, requires);
+                let head = trivia.head(
+                    Some(id).filter(|_| ids),
+                    emit_head(name, requires, &self.defaults(), provides, annotations),
+                );
                 let provides = provides
                     .iter()
                     .map(|s| s.clone())
                     .collect::<Vec<String>>()
                     .join(", ");
-                let ordering = topo_sort(nodes.values().collect());
+                let ordering = topo_sort(nodes.values().collect())?;
                 let mut body: Vec<String> = vec![];
                 for k in ordering.iter().flatten() {
-                    let (requires, provides) = nodes.get(k).unwrap().deps();
+                    let node = nodes.get(k).unwrap();
+                    let (requires, provides) = node.deps();
                     let provides = provides
                         .iter()
                         .map(|s| s.clone())
                         .collect::<Vec<String>>()
                         .join(", ");
-                    let requires = requires
-                        .iter()
-                        .map(|s| s.clone())
-                        .collect::<Vec<String>>()
-                        .join(", ");
-                    body.push(format!("    [{}] = {}({})", provides, k, requires));
+                    let requires = node.call_args().join(", ");
+                    if let Some(before) = trivia.node_before.get(k) {
+                        body.push(indent(before));
+                    }
+                    let inline = trivia.node_inline.get(k).map_or("", |s| s.as_str());
+                    body.push(format!(
+                        "    [{}] = {}({}){}",
+                        provides, k, requires, inline
+                    ));
+                }
+                if !trivia.before_return.is_empty() {
+                    body.push(indent(&trivia.before_return));
                 }
-                format!(
-                    "{}\r\n{}\r\n    return [{}]",
+                Ok(format!(
+                    "{}\r\n{}\r\n    return [{}]{}",
                     head,
                     body.join("\r\n"),
-                    provides
-                )
+                    provides,
+                    trivia.tail()
+                ))
+            }
+        }
+    }
+
+    /// Whether calling this node gives a coroutine, once emitted by
+    /// `emit_async`.
+    fn is_coroutine(&self) -> bool {
+        match self {
+            Tangle::Leaf { code, .. } => scope::awaits(code),
+            Tangle::Dataflow { .. } => true,
+        }
+    }
+
+    /// The arguments a flow calls this node with: its requires, then its
+    /// constants, which are passed down from the flow parameters.
+    fn call_args(&self) -> Vec<String> {
+        let constants = match self {
+            Tangle::Leaf { name, defaults, .. } => defaults
+                .keys()
+                .map(|p| format!("{}={}_{}", p, name, p))
+                .collect::<Vec<_>>(),
+            Tangle::Dataflow { .. } => self
+                .defaults()
+                .keys()
+                .map(|p| format!("{}={}", p, p))
+                .collect(),
+        };
+        self.deps().0.iter().cloned().chain(constants).collect()
+    }
+
+    /// Emits a flow as an `async def` that runs each level of `topo_sort`
+    /// with `asyncio.gather`. Sub-flows and leaves that await are awaited,
+    /// other leaves run in the default executor. Nodes changing an input
+    /// other nodes of their level read run on their own.
+    fn emit_async_flow(&self) -> Result<String, Error> {
+        let (name, nodes, annotations, trivia) = match self {
+            Tangle::Leaf { .. } => return self.emit_with(false, true),
+            Tangle::Dataflow {
+                name,
+                nodes,
+                annotations,
+                trivia,
+                ..
+            } => (name, nodes, annotations, trivia),
+        };
+        let (requires, provides) = self.deps();
+        let head = trivia.head(
+            None,
+            format!(
+                "async {}",
+                emit_head(name, requires, &self.defaults(), provides, annotations)
+            ),
+        );
+        // parsed once, `is_coroutine` parses the code of leaves
+        let blocking: IndexSet<&String> = nodes
+            .iter()
+            .filter(|(_, node)| !node.is_coroutine())
+            .map(|(k, _)| k)
+            .collect();
+        let mut body: Vec<String> = vec![];
+        if !blocking.is_empty() {
+            body.push("    _loop = asyncio.get_running_loop()".to_string());
+        }
+        let mut groups: Vec<Vec<String>> = vec![];
+        for level in topo_sort(nodes.values().collect())? {
+            // nodes changing a variable another node of the level reads run
+            // one at a time, after the others
+            let changes: IndexMap<&String, IndexSet<String>> = level
+                .iter()
+                .map(|k| (k, nodes[k].mutations().into_keys().collect()))
+                .collect();
+            let reads = |k: &String, vars: &IndexSet<String>| {
+                vars.iter().any(|var| nodes[k].deps().0.contains(var))
+            };
+            let (alone, together): (Vec<String>, Vec<String>) =
+                level.iter().cloned().partition(|k| {
+                    level
+                        .iter()
+                        .filter(|other| *other != k)
+                        .any(|other| reads(other, &changes[k]) || reads(k, &changes[other]))
+                });
+            groups.push(together);
+            groups.extend(alone.into_iter().map(|k| vec![k]));
+        }
+        for level in groups {
+            if level.is_empty() {
+                continue;
+            }
+            let mut targets = vec![];
+            let mut calls = vec![];
+            for k in &level {
+                let node = &nodes[k];
+                if let Some(before) = trivia.node_before.get(k) {
+                    body.push(indent(before));
+                }
+                let provides = node.deps().1.iter().cloned().collect::<Vec<_>>();
+                targets.push(format!("[{}]", provides.join(", ")));
+                let args = node.call_args();
+                calls.push(if !blocking.contains(k) {
+                    format!("{}({})", k, args.join(", "))
+                } else if args.is_empty() {
+                    format!("_loop.run_in_executor(None, {})", k)
+                } else {
+                    format!(
+                        "_loop.run_in_executor(None, functools.partial({}, {}))",
+                        k,
+                        args.join(", ")
+                    )
+                });
+            }
+            if let [k] = level.as_slice() {
+                let inline = trivia.node_inline.get(k).map_or("", |s| s.as_str());
+                body.push(format!("    {} = await {}{}", targets[0], calls[0], inline));
+            } else {
+                body.push(format!(
+                    "    {} = await asyncio.gather(",
+                    targets.join(", ")
+                ));
+                for (k, call) in level.iter().zip(&calls) {
+                    let inline = trivia.node_inline.get(k).map_or("", |s| s.as_str());
+                    body.push(format!("        {},{}", call, inline));
+                }
+                body.push("    )".to_string());
             }
         }
+        if !trivia.before_return.is_empty() {
+            body.push(indent(&trivia.before_return));
+        }
+        Ok(format!(
+            "{}\r\n{}\r\n    return [{}]{}",
+            head,
+            body.join("\r\n"),
+            provides.iter().cloned().collect::<Vec<_>>().join(", "),
+            trivia.tail()
+        ))
+    }
+
+    /// Every flow defined in `code`, keyed by name. A function is a flow if
+    /// one of its node calls names a function defined in `code`; functions
+    /// that only call library code are leaves. Fails if no flow parses,
+    /// otherwise the errors of the others are returned with the flows.
+    pub fn from_code(code: String) -> Result<Parsed, Error> {
+        Tangle::from_code_with(&QueryPack::active(), code)
     }
 
-    pub fn from_code(code: String) -> Result<Tangle, Error> {
+    /// `from_code` with the queries of `pack` instead of the active ones.
+    pub fn from_code_with(pack: &QueryPack, code: String) -> Result<Parsed, Error> {
         let ts = TangleString::from(code.as_str());
         let mut parser = tree_sitter::Parser::new();
         parser.set_language(tree_sitter_python::language()).unwrap();
         let tree = parser.parse(&code, None).unwrap();
         let root = tree.root_node();
-        let sp = sexp::parse(root.to_sexp().as_str()).unwrap();
-        println!("{:#?}", sp);
         let mut leaves: IndexMap<String, Tangle> = Default::default();
-        let mut others: Vec<QueryMatch> = vec![];
+        // where each leaf is defined, with its decorators
+        let mut outers: IndexMap<String, std::ops::Range<usize>> = Default::default();
+        let mut flows: IndexMap<String, Vec<(Node, String)>> = Default::default();
         let mut cursor = tree_sitter::QueryCursor::new();
-        for qm in cursor.matches(&QUERY, tree.root_node(), ts.clone()) {
-            if qm.nodes_for_capture_index(*FLOW_NODE).next().is_none() {
-                // leaf pattern
-                let leaf = Tangle::leaf_from_match(&qm, &ts);
-                leaves.insert(leaf.name().clone(), leaf);
-            } else {
-                let leaf = Tangle::leaf_from_match(&qm, &ts);
-                leaves.insert(leaf.name().clone(), leaf);
-                others.push(qm);
+        let mut prev_end = 0;
+        let mut bad: IndexMap<String, Error> = Default::default();
+        let mut defined: IndexSet<String> = Default::default();
+        for qm in cursor.matches(&pack.leaf, tree.root_node(), ts.clone()) {
+            let outer = match Tangle::outer_node(pack, &qm)? {
+                // a pack may match a function with more than one pattern
+                Some(outer) if outer.start_byte() >= prev_end => outer,
+                _ => continue,
+            };
+            let name = pack.leaf_capture(&qm, pack.leaf_name)?;
+            defined.insert(code[name.byte_range()].to_string());
+            let leaf = match Tangle::leaf_from_match(pack, &qm, &ts) {
+                Ok(Some(leaf)) => leaf,
+                // kept as module code, reported if a flow calls it
+                Ok(None) => continue,
+                Err(err) => {
+                    bad.insert(code[name.byte_range()].to_string(), err);
+                    continue;
+                }
+            };
+            let block = pack.leaf_capture(&qm, pack.leaf_body)?;
+            let end = final_return(block).map_or(block.end_byte(), |r| r.start_byte());
+            let calls = node_calls(pack, block, end, &ts)?;
+            if !calls.is_empty() {
+                // flow pattern, resolved once every function has been seen
+                flows.insert(leaf.name().clone(), calls);
+            }
+            prev_end = outer.end_byte();
+            outers.insert(leaf.name().clone(), outer.byte_range());
+            leaves.insert(leaf.name().clone(), leaf);
+        }
+
+        flows.retain(|_, calls| calls.iter().any(|(_, callee)| defined.contains(callee)));
+
+        // functions no flow uses are kept as module code around the others
+        let used: IndexSet<String> = flows
+            .iter()
+            .flat_map(|(name, calls)| {
+                std::iter::once(name.clone()).chain(calls.iter().map(|(_, callee)| callee.clone()))
+            })
+            .collect();
+        let mut prev_end = 0;
+        let mut last = None;
+        for (name, outer) in &outers {
+            if !used.contains(name) {
+                continue;
+            }
+            if let Some(Tangle::Leaf { trivia, .. }) = leaves.get_mut(name) {
+                let mut before = lines_between(&code, prev_end..line_start(&code, outer.start), 0)
+                    .lines()
+                    .skip_while(|l| l.trim().is_empty())
+                    .map(|l| l.to_string())
+                    .collect::<Vec<_>>();
+                if before.last().is_some_and(|l| l.starts_with(ID_MARKER)) {
+                    before.pop();
+                }
+                trivia.before = before.join("\r\n");
             }
+            prev_end = outer.end;
+            last = Some(name);
+        }
+        if let Some(Tangle::Leaf { trivia, .. }) = last.and_then(|l| leaves.get_mut(l)) {
+            let after = &code[prev_end..];
+            let after = after.trim_end_matches(['\n', '\r']);
+            trivia.after = after.lines().collect::<Vec<_>>().join("\r\n");
         }
 
-        for qm in others.into_iter() {
-            // flow pattern
-            match Tangle::flow_from_match(&qm, &leaves, &code) {
+        let mut parsed: IndexMap<String, Tangle> = Default::default();
+        let mut errors = vec![];
+        for name in flows.keys() {
+            match Tangle::flow_from_nodes(
+                pack,
+                name,
+                &flows,
+                &leaves,
+                &code,
+                &mut Default::default(),
+            ) {
                 Ok(flow) => {
-                    if flow.name() == "dataflow" {
-                        return Ok(flow);
-                    } else {
-                        dbg!(flow);
-                    }
+                    parsed.insert(name.clone(), flow);
                 }
                 Err(why) => {
-                    dbg!(why);
+                    let why = match why.into_kind() {
+                        ErrorKind::MissingLeaf { name, span } => match bad.remove(&name) {
+                            Some(err) => err,
+                            None => ErrorKind::MissingLeaf { name, span }.into(),
+                        },
+                        why => why.into(),
+                    };
+                    errors.push(why);
                 }
             }
         }
-        Err(Error::NoDataflowFound)
+        if parsed.is_empty() {
+            Err(errors
+                .into_iter()
+                .next()
+                .or_else(|| bad.into_iter().map(|(_, err)| err).next())
+                .unwrap_or_else(|| {
+                    ErrorKind::NoDataflowFound {
+                        span: Span::from(root),
+                    }
+                    .into()
+                }))
+        } else {
+            let warnings = parsed
+                .values()
+                .flat_map(|flow| flow.type_mismatches())
+                .collect();
+            Ok(Parsed {
+                flows: parsed,
+                errors,
+                warnings,
+            })
+        }
+    }
+}
+
+/// The flows of a file, see `Tangle::from_code`.
+pub struct Parsed {
+    pub flows: IndexMap<String, Tangle>,
+    /// why the functions that call other functions of the file are not flows
+    pub errors: Vec<Error>,
+    /// `ErrorKind::TypeMismatch`es of the flows
+    pub warnings: Vec<Error>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(code: &str) -> Result<IndexMap<String, Tangle>, Error> {
+        Tangle::from_code(code.to_string()).map(|parsed| parsed.flows)
+    }
+
+    #[test]
+    fn nested_flow() {
+        let flows = parse(
+            "def one():\n    x = 1\n    return [x]\n\n\
+             def inc(x):\n    y = x + 1\n    return [y]\n\n\
+             def prep(x):\n    y = inc(x)\n    return y\n\n\
+             def main():\n    x = one()\n    y = prep(x)\n    return y\n",
+        )
+        .unwrap();
+        let nodes = match &flows["main"] {
+            Tangle::Dataflow { nodes, .. } => nodes,
+            _ => panic!("main is not a flow"),
+        };
+        assert!(matches!(nodes["one"], Tangle::Leaf { .. }));
+        assert!(matches!(nodes["prep"], Tangle::Dataflow { .. }));
+    }
+
+    #[test]
+    fn library_calls_are_leaves() {
+        let flows = parse(
+            "def load(path):\n    df = read_csv(path)\n    return [df]\n\n\
+             def main(path):\n    df = load(path)\n    return [df]\n",
+        )
+        .unwrap();
+        assert_eq!(flows.keys().collect::<Vec<_>>(), vec!["main"]);
+        match &flows["main"] {
+            Tangle::Dataflow { nodes, .. } => {
+                assert!(matches!(nodes["load"], Tangle::Leaf { .. }))
+            }
+            _ => panic!("main is not a flow"),
+        }
+    }
+
+    #[test]
+    fn nested_flow_errors_propagate() {
+        let err = parse(
+            "def one():\n    x = 1\n    return [x]\n\n\
+             def inc(x):\n    y = x + 1\n    return [y]\n\n\
+             def prep(x):\n    y = inc(z)\n    return y\n\n\
+             def main():\n    x = one()\n    y = prep(x)\n    return y\n",
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::RequiresMismatch { .. }),
+            "{}",
+            err
+        );
+    }
+
+    fn leaf(name: &str, requires: &[&str], provides: &[&str]) -> Tangle {
+        let mut leaf = Tangle::default();
+        if let Tangle::Leaf {
+            name: n,
+            requires: r,
+            provides: p,
+            ..
+        } = &mut leaf
+        {
+            *n = name.to_string();
+            *r = requires.iter().map(|s| s.to_string()).collect();
+            *p = provides.iter().map(|s| s.to_string()).collect();
+        }
+        leaf
+    }
+
+    fn flow(name: &str, nodes: Vec<Tangle>) -> Tangle {
+        Tangle::from_leaves(
+            name.to_string(),
+            nodes.into_iter().map(|n| (n.name().clone(), n)).collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn cycles() {
+        let a = leaf("a", &["y"], &["x"]);
+        let b = leaf("b", &["x"], &["y"]);
+        let c = leaf("c", &["y"], &["z"]);
+        let cycles = find_cycles(vec![&a, &b, &c]);
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].nodes.iter().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(cycles[0].vars.iter().collect::<Vec<_>>(), vec!["y", "x"]);
+        let err = topo_sort(vec![&a, &b, &c]).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Cycle { cycles, .. } if cycles.len() == 1));
+    }
+
+    #[test]
+    fn reassigning_a_requirement_is_not_a_cycle() {
+        let a = leaf("a", &["x"], &["x"]);
+        let b = leaf("b", &["x"], &["y"]);
+        assert!(find_cycles(vec![&a, &b]).is_empty());
+    }
+
+    fn node_names(flow: &Tangle) -> Vec<String> {
+        match flow {
+            Tangle::Dataflow { nodes, .. } => nodes.keys().cloned().collect(),
+            Tangle::Leaf { name, .. } => vec![name.clone()],
+        }
+    }
+
+    #[test]
+    fn slice() {
+        let inner = flow(
+            "inner",
+            vec![leaf("c", &["x"], &["y"]), leaf("d", &["x"], &["z"])],
+        );
+        let outer = flow(
+            "outer",
+            vec![
+                leaf("a", &[], &["x"]),
+                leaf("b", &[], &["w"]),
+                inner,
+                leaf("e", &["z"], &["v"]),
+            ],
+        );
+        let sliced = outer.slice_for(&["v"]).unwrap();
+        assert_eq!(node_names(&sliced), vec!["a", "inner", "e"]);
+        match &sliced {
+            Tangle::Dataflow { nodes, .. } => assert_eq!(node_names(&nodes["inner"]), vec!["d"]),
+            _ => panic!("a slice of a flow is a flow"),
+        }
+        let err = outer.slice_for(&["u"]).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::UnknownVariable { var, .. } if var == "u"));
+        let err = leaf("a", &[], &["x"]).slice_for(&["y"]).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::UnknownVariable { var, .. } if var == "y"));
+    }
+
+    #[test]
+    fn impact() {
+        let inner = flow(
+            "inner",
+            vec![leaf("c", &["x"], &["y"]), leaf("d", &["w"], &["z"])],
+        );
+        let outer = flow(
+            "outer",
+            vec![
+                leaf("a", &[], &["x"]),
+                leaf("b", &[], &["w"]),
+                inner,
+                leaf("e", &["y"], &["v"]),
+                leaf("f", &["z"], &["u"]),
+            ],
+        );
+        let impact = |node| outer.impact_of(node).into_iter().collect::<Vec<_>>();
+        assert_eq!(impact("a"), vec!["c", "e"]);
+        assert_eq!(impact("d"), vec!["f"]);
+        assert!(impact("f").is_empty());
+    }
+
+    fn annotated(mut leaf: Tangle, var: &str, ty: &str) -> Tangle {
+        if let Tangle::Leaf { annotations, .. } = &mut leaf {
+            annotations.insert(var.to_string(), ty.to_string());
+        }
+        leaf
+    }
+
+    #[test]
+    fn type_mismatches_are_warnings() {
+        let flows = parse(
+            "def one() -> int:\n    x = 1\n    return [x]\n\n\
+             def two(x: str):\n    y = x\n    return [y]\n\n\
+             def main():\n    x = one()\n    y = two(x)\n    return y\n",
+        )
+        .unwrap();
+        assert!(flows["main"].emit_all().is_ok());
+        let warnings = flows["main"].type_mismatches();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            "`x` is `int` in one but `str` in two"
+        );
+        let err = flows["main"].check_types().unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::TypeMismatch { var, consumer, .. }
+            if var == "x" && consumer == "two")
+        );
+        assert_eq!(err.span().map(|span| span.start.row), Some(4));
+    }
+
+    #[test]
+    fn annotations_do_not_depend_on_node_order() {
+        let a = annotated(leaf("a", &[], &["x"]), "x", "int");
+        let b = annotated(leaf("b", &["x", "y"], &["z"]), "x", "str");
+        let b = annotated(b, "y", "float");
+        let c = annotated(leaf("c", &["y"], &["w"]), "y", "bytes");
+        for nodes in [vec![&a, &b, &c], vec![&c, &b, &a]] {
+            let flow = flow("f", nodes.into_iter().cloned().collect());
+            assert_eq!(flow.annotation("x").map(|s| s.as_str()), Some("int"));
+            assert_eq!(flow.annotation("y"), None);
+        }
+    }
+
+    #[test]
+    fn ids_for_names_are_fixed() {
+        assert_eq!(id_for_name(""), "cbf29ce484222325");
+        assert_eq!(id_for_name("a"), "af63dc4c8601ec8c");
+        assert_eq!(id_for_name("foobar"), "85944171f73967e8");
+    }
+
+    #[test]
+    fn ids_are_only_in_source() {
+        let code = "def one():\n    x = 1\n    return [x]\n\n\
+                    def main():\n    x = one()\n    return x\n";
+        let flows = parse(code).unwrap();
+        let main = &flows["main"];
+        let source = main.emit_all().unwrap();
+        assert!(source.contains(&format!("{} {}", ID_MARKER, id_for_name("one"))));
+        let reparsed = parse(&source).unwrap();
+        assert_eq!(reparsed["main"].leaves()[0].1.id(), &id_for_name("one"));
+        for emitted in [
+            main.emit_decorated(&Some("@memo")).unwrap(),
+            main.emit_async().unwrap(),
+            main.emit_script(false).unwrap(),
+            main.emit_script(true).unwrap(),
+        ] {
+            assert!(!emitted.contains(ID_MARKER), "{}", emitted);
+        }
+    }
+
+    #[test]
+    fn module_code_is_kept_in_source_order() {
+        let code = "import os\n\n\
+                    def unused():\n    return 1\n\n\
+                    X = 1\n\n\
+                    def two():\n    y = 2\n    return [y]\n\n\
+                    def one():\n    x = 1\n    return [x]\n\n\
+                    def main():\n    [x] = one()\n    [y] = two()\n    return [x, y]\n\n\
+                    def unused_after():\n    return 3\n\n\
+                    # the end\n";
+        let source = parse(code).unwrap()["main"].emit_all().unwrap();
+        let source: String = source
+            .replace("\r\n", "\n")
+            .lines()
+            .filter(|l| !l.starts_with(ID_MARKER))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        assert_eq!(source.replace("\n\n", "\n"), code.replace("\n\n", "\n"));
+    }
+
+    #[test]
+    fn call_site_defaults() {
+        let code = "def one():\n    x = 1\n    return [x]\n\n\
+                    def two(x, scale=1.0, shift=0):\n    y = x * scale + shift\n    return [y]\n\n\
+                    def main(two_shift=2):\n    [x] = one()\n    [y] = two(x, scale=3.0)\n    return [y]\n";
+        let main = &parse(code).unwrap()["main"];
+        let defaults = main.defaults();
+        assert_eq!(defaults["two_scale"], "3.0");
+        assert_eq!(defaults["two_shift"], "2");
+        let reparsed = parse(&main.emit_all().unwrap()).unwrap();
+        assert_eq!(reparsed["main"].defaults(), defaults);
+
+        let err = parse(&code.replace("scale=3.0", "size=3.0")).unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::UnknownDefault { param, .. } if param == "size"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn script_options() {
+        let code = "def two(out, scale: float = 1.0):\n    y = out * scale\n    return [y]\n\n\
+                    def main(out):\n    [y] = two(out)\n    return [y]\n";
+        let script = parse(code).unwrap()["main"].emit_script(false).unwrap();
+        assert!(
+            script.contains("\"--out\", type=_value, required=True"),
+            "{}",
+            script
+        );
+        assert!(
+            script.contains("\"--two_scale\", type=float, default=1.0"),
+            "{}",
+            script
+        );
+        assert!(script.contains("\"--_out\", dest=\"_out\""), "{}", script);
+    }
+
+    #[test]
+    fn async_levels_keep_changed_inputs_apart() {
+        let code = "def grow(xs):\n    xs.append(2)\n    n = len(xs)\n    return [n]\n\n\
+                    def count(xs):\n    m = len(xs)\n    return [m]\n\n\
+                    def one(ys):\n    k = ys\n    return [k]\n\n\
+                    def two(ys):\n    j = ys\n    return [j]\n\n\
+                    def main(xs, ys):\n    [n] = grow(xs)  # grows\n    [m] = count(xs)\n    \
+                    [k] = one(ys)\n    [j] = two(ys)  # two\n    return [n, m, k, j]\n";
+        let source = parse(code).unwrap()["main"].emit_async().unwrap();
+        let main = &source[source.find("async def main").unwrap()..];
+        let lines: Vec<&str> = main.lines().map(|l| l.trim_end_matches('\r')).collect();
+        assert_eq!(
+            lines[2..8],
+            [
+                "    [k], [j] = await asyncio.gather(",
+                "        _loop.run_in_executor(None, functools.partial(one, ys)),",
+                "        _loop.run_in_executor(None, functools.partial(two, ys)),  # two",
+                "    )",
+                "    [n] = await _loop.run_in_executor(None, functools.partial(grow, xs))  # grows",
+                "    [m] = await _loop.run_in_executor(None, functools.partial(count, xs))",
+            ]
+        );
+    }
+
+    #[test]
+    fn awaiting_leaves_need_async() {
+        let code = "def wait():\n    x = await fetch()\n    return [x]\n\n\
+                    def main():\n    [x] = wait()\n    return [x]\n";
+        let main = &parse(code).unwrap()["main"];
+        let err = main.emit_all().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::SyncAwait { name, .. } if name == "wait"));
+        assert_eq!(err.span().map(|span| span.start.row), Some(0));
+        let source = main.emit_async().unwrap();
+        assert!(source.contains("async def wait():"), "{}", source);
+        assert!(source.contains("[x] = await wait()"), "{}", source);
+    }
+
+    #[test]
+    fn conflicting_defaults() {
+        let mut a = leaf("a", &[], &["x"]);
+        a.defaults_mut()
+            .unwrap()
+            .insert("b_c".to_string(), "1".to_string());
+        let mut a_b = leaf("a_b", &["x"], &["y"]);
+        a_b.defaults_mut()
+            .unwrap()
+            .insert("c".to_string(), "2".to_string());
+        let nodes = vec![("a".to_string(), a), ("a_b".to_string(), a_b)];
+        let err = Tangle::from_leaves("main".to_string(), nodes.into_iter().collect()).unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::ConflictingDefaults { param, .. } if param == "a_b_c"),
+            "{}",
+            err
+        );
+    }
+
+    fn cell(name: &str, code: &str) -> Tangle {
+        Tangle::from_cell(name.to_string(), &code.to_string())
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn mutations_order_readers() {
+        let nodes = [
+            cell("a", "xs = []"),
+            cell("c", "n = len(xs)"),
+            cell("b", "ok = True\nxs.append(1)"),
+        ];
+        let found = find_mutations(nodes.iter().collect());
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].to_string(),
+            "`b` probably changes `xs` of `a` in place on line 2, nodes reading it may see stale data"
+        );
+        let order = topo_sort(nodes.iter().collect()).unwrap();
+        assert_eq!(order.concat(), ["a", "b", "c"]);
+        // the layout of the graph itself is left alone
+        assert_eq!(
+            topo_layout(nodes.iter().collect()).concat(),
+            ["a", "c", "b"]
+        );
+
+        let nodes = [cell("a", "xs = []"), cell("b", "xs[0] = 1\nok = True")];
+        let found = find_mutations(nodes.iter().collect());
+        assert!(!found[0].guessed);
+    }
+
+    #[test]
+    fn merge_orders_flows() {
+        let up = flow("up", vec![leaf("a", &[], &["x"])]);
+        let down = flow("down", vec![leaf("b", &["x", "y"], &["z"])]);
+        let merged = Tangle::merge(&down, &up).unwrap();
+        assert_eq!(merged.name(), "down_up");
+        let (requires, provides) = merged.deps();
+        assert_eq!(requires.iter().collect::<Vec<_>>(), vec!["y"]);
+        assert_eq!(provides.iter().collect::<Vec<_>>(), vec!["x", "z"]);
+        match merged {
+            Tangle::Dataflow { nodes, .. } => {
+                assert_eq!(nodes.keys().collect::<Vec<_>>(), vec!["a", "b"])
+            }
+            _ => panic!("merge gives a flow"),
+        }
+    }
+
+    #[test]
+    fn merge_errors() {
+        let one = flow("one", vec![leaf("a", &["y"], &["x"])]);
+        let two = flow("two", vec![leaf("b", &["x"], &["y"])]);
+        let err = Tangle::merge(&one, &two).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::CircularMerge { .. }));
+        let three = flow("three", vec![leaf("a", &[], &["z"])]);
+        let err = Tangle::merge(&one, &three).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::ConflictingMerge { .. }));
+    }
+
+    #[test]
+    fn literal_arguments_are_not_node_calls() {
+        let flows = parse(
+            "def helper(x):\n    return x\n\n\
+             def load():\n    x = helper(1)\n    return x\n\n\
+             def main():\n    x = load()\n    return x\n",
+        )
+        .unwrap();
+        assert_eq!(flows.keys().collect::<Vec<_>>(), vec!["main"]);
+    }
+
+    #[test]
+    fn graph_errors_point_at_nodes() {
+        let flows = parse(
+            "def a(y):\n    x = y\n    return [x]\n\n\
+             def b(x):\n    y = x\n    return [y]\n\n\
+             def main():\n    x = a(y)\n    y = b(x)\n    return y\n",
+        )
+        .unwrap();
+        let err = flows["main"].emit_all().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Cycle { .. }));
+        assert_eq!(err.span().map(|span| span.start.row), Some(0));
+    }
+
+    #[test]
+    fn render() {
+        let code = "def one():\n    x = 1\n    return [x]\n\n\
+                    def main():\n    y = one()\n    return y\n";
+        let err = parse(code).unwrap_err();
+        assert_eq!(
+            err.render(code, "flow.py"),
+            "error: assigns [y] but the definition returns [x]\n \
+             --> flow.py:6:5\n  |\n6 |     y = one()\n  |     ^^^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn errors_of_other_flows_are_returned() {
+        let parsed = Tangle::from_code(
+            "def one():\n    x = 1\n    return [x]\n\n\
+             def good():\n    x = one()\n    return x\n\n\
+             def bad():\n    [x, y] = one()\n    return x\n"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(parsed.flows.keys().collect::<Vec<_>>(), vec!["good"]);
+        assert_eq!(parsed.errors.len(), 1);
+        assert!(matches!(
+            parsed.errors[0].kind(),
+            ErrorKind::ProvidesMismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn recursive_flow() {
+        let err = parse(
+            "def one():\n    x = 1\n    return [x]\n\n\
+             def main(x):\n    x = one()\n    y = main(x)\n    return y\n",
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::RecursiveFlow { .. }),
+            "{}",
+            err
+        );
+    }
+
+    fn split(code: &str) -> Vec<String> {
+        Tangle::split_script("step".to_string(), &code.to_string())
+            .unwrap()
+            .leaves()
+            .iter()
+            .map(|(_, leaf)| leaf.code().clone())
+            .collect()
+    }
+
+    #[test]
+    fn split_script_by_paragraph() {
+        assert_eq!(
+            split(
+                "import os\nimport sys\nroot = os.getcwd()\nargs = sys.argv\nprint(args)\n\n\
+                 # count\nn = len(args)\n\nroot = root + '/'\n"
+            ),
+            [
+                "import os\nimport sys",
+                "root = os.getcwd()\nargs = sys.argv\nprint(args)\n\n\
+                 # count\nn = len(args)\n\nroot = root + '/'",
+            ]
+        );
+        assert_eq!(
+            split("a = 1\nb = 2\n\nc = a + b\nprint(c)\n"),
+            ["a = 1\nb = 2", "c = a + b\nprint(c)"]
+        );
+    }
+
+    #[test]
+    fn split_script_keeps_comments() {
+        assert_eq!(split("# just notes\n# more\n"), ["# just notes\n# more\n"]);
+        assert_eq!(split("x = 1\n# done\n"), ["x = 1\n# done\n"]);
+    }
+
+    fn parse_with(leaf_query: &str, code: &str) -> Result<IndexMap<String, Tangle>, Error> {
+        let sources = [
+            leaf_query,
+            include_str!("node_query.scm"),
+            include_str!("cell_query.scm"),
+        ];
+        let pack = QueryPack::from_sources(sources).unwrap();
+        Tangle::from_code_with(&pack, code.to_string()).map(|parsed| parsed.flows)
+    }
+
+    #[test]
+    fn decorated_leaves() {
+        let query = "(decorated_definition\n  \
+                       (decorator (attribute) @_deco (#eq? @_deco \"tangle.node\"))\n  \
+                       definition: (function_definition name: (identifier) @leaf.name\n    \
+                         parameters: (parameters) @leaf.param body: (block) @leaf.body)) @leaf";
+        let flows = parse_with(
+            query,
+            "@tangle.node\ndef load():\n    xs = [1, 2]\n    return xs\n\n\
+             def helper(xs):\n    return xs\n\n\
+             @tangle.node\ndef main():\n    xs = load()\n    return xs\n",
+        )
+        .unwrap();
+        let nodes = match &flows["main"] {
+            Tangle::Dataflow { nodes, .. } => nodes,
+            _ => panic!("main is not a flow"),
+        };
+        assert_eq!(flows.keys().collect::<Vec<_>>(), vec!["main"]);
+        assert_eq!(nodes.keys().collect::<Vec<_>>(), vec!["load"]);
+        assert_eq!(nodes["load"].code().trim(), "xs = [1, 2]");
+        assert_eq!(nodes["load"].trivia().decorators, vec!["@tangle.node"]);
+    }
+
+    #[test]
+    fn dataclass_leaves() {
+        let query = format!(
+            "{}\n(decorated_definition\n  \
+               (decorator (identifier) @_deco (#eq? @_deco \"dataclass\"))\n  \
+               definition: (class_definition name: (identifier) @leaf.name\n    \
+                 superclasses: (argument_list)? @leaf.param body: (block) @leaf.body)) @leaf",
+            include_str!("query.scm")
+        );
+        let flows = parse_with(
+            &query,
+            "@dataclass\nclass Config:\n    lr: float = 0.1\n    epochs = 3\n\n\
+             def train(lr, epochs):\n    w = lr * epochs\n    return w\n\n\
+             def main():\n    lr, epochs = Config()\n    w = train(lr, epochs)\n    return w\n",
+        )
+        .unwrap();
+        let nodes = match &flows["main"] {
+            Tangle::Dataflow { nodes, .. } => nodes,
+            _ => panic!("main is not a flow"),
+        };
+        let config = &nodes["Config"];
+        assert_eq!(
+            config.deps().1.iter().collect::<Vec<_>>(),
+            vec!["lr", "epochs"]
+        );
+        assert_eq!(config.code().trim(), "lr: float = 0.1\r\nepochs = 3");
+        assert!(config.trivia().decorators.is_empty());
+    }
+
+    #[test]
+    fn patterns_without_captures() {
+        let query = "[\n  \
+                       (function_definition name: (identifier) @leaf.name\n    \
+                         parameters: (parameters) @leaf.param body: (block) @leaf.body)\n  \
+                       (class_definition body: (block))\n] @leaf";
+        let err = parse_with(query, "class A:\n    x = 1\n").unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::InvalidQuery { file, message, .. }
+                if file == "query.scm" && message.contains("@leaf.name")
+        ));
     }
 }
@@ -1,8 +1,7 @@
 import inspect
 import dis
-from types import CodeType, FunctionType
+from types import FunctionType
 from typing import Any, Dict, Union
-import textwrap
 
 import matplotlib
 matplotlib.use("module://tangle.mpl_backend")
@@ -43,16 +42,20 @@ def eq(x, y):
 def memo(name):
     def do_memo(fn):
         prev_args = None
-        def memoized(*args):
-            nonlocal prev_args
+        prev_kwargs = None
+        def memoized(*args, **kwargs):
+            nonlocal prev_args, prev_kwargs
             # it has to be any(not) becaue we want to find the first mismatching arg
             # not any() would find the first matching arg, but would ignore future
             # mismatching arguments
-            if prev_args is None or any(not eq(arg, prev_arg) for arg, prev_arg in zip(args, prev_args)):
+            if prev_args is None or any(not eq(arg, prev_arg) for arg, prev_arg in zip(args, prev_args)) \
+                    or kwargs.keys() != prev_kwargs.keys() \
+                    or any(not eq(v, prev_kwargs[k]) for k, v in kwargs.items()):
                 prev_args = args
+                prev_kwargs = kwargs
                 #py
                 memoized._changed = True
-                memoized._prev_return = try_or_exn(fn, *args)
+                memoized._prev_return = try_or_exn(fn, *args, **kwargs)
                 return memoized._prev_return
             else:
                 memoized._changed = False
@@ -62,7 +65,7 @@ def memo(name):
         memoized._changed = False
         memoized._prev_return = []
         curr_plots = []
-        def wrapped(*args):
+        def wrapped(*args, **kwargs):
             def show(ui):
                 nonlocal curr_plots
                 global CURR_PLOTS
@@ -70,7 +73,7 @@ def memo(name):
                 set_ui_ref(ui)
                 CURR_PLOTS.clear()
                 plt.clf()
-                ret = memoized(*args)
+                ret = memoized(*args, **kwargs)
                 if memoized._changed:
                     curr_plots = CURR_PLOTS[:]
 
@@ -100,26 +103,6 @@ def memo(name):
         return wrapped
     return do_memo
 
-def cell_load_globals(code: str, exclude_globals: Dict[str, Any]):
-    if code.rstrip() == "":
-        return []
-    indented = textwrap.indent(code, "    ")
-    func = f"def cell():\r\n{indented}"
-    code = compile(func, "<cell>", "exec")
-    cell_code = next(dis.get_instructions(code)).argval
-    gbs = set()
-    for ins in iter_globals(cell_code):
-        if ins.argval not in exclude_globals:
-            gbs.add(ins.argval)
-    return list(gbs)
-
-def iter_globals(code: CodeType):
-    for ins in dis.get_instructions(code):
-        if ins.opname == 'LOAD_GLOBAL':
-            yield ins
-        elif type(ins.argval) == CodeType:
-            yield from iter_globals(ins.argval)
-        
 
 def compile_cell(name, code: str, provides):
     code = code.rstrip()